@@ -4,7 +4,14 @@ use mrsbfh::commands::command_generate;
 
 pub mod hello_world;
 
-#[command_generate(bot_name = "Example", description = "This bot prints hello!")]
+#[command_generate(
+    bot_name = "Example",
+    description = "This bot prints hello!",
+    mention = true,
+    suggestions = true,
+    state = Config<'static>,
+    error = Error
+)]
 enum Commands {
     Hello_World,
 }
@@ -9,4 +9,6 @@ pub struct Config<'a> {
     pub password: Cow<'a, str>,
     pub store_path: Cow<'a, str>,
     pub session_path: Cow<'a, str>,
+    #[serde(default)]
+    pub commands: mrsbfh::config::CommandsConfig,
 }
@@ -6,3 +6,5 @@ pub enum Error {
     #[error(transparent)]
     SendError(#[from] tokio::sync::mpsc::error::SendError<AnyMessageEventContent>),
 }
+
+impl mrsbfh::errors::CommandError for Error {}
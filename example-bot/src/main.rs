@@ -25,6 +25,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .init();
 
     info!("Starting...");
+    // Messages sent before this don't run commands
+    mrsbfh::sync::startup_time();
     let opts: Opts = Opts::parse();
 
     info!("Loading Configs...");
@@ -3,7 +3,6 @@ use matrix_sdk::{Client, ClientConfig, Session as SDKSession, SyncSettings};
 use mrsbfh::url::Url;
 use mrsbfh::utils::Session;
 use std::{convert::TryFrom, error::Error, fs, path::Path, sync::Arc};
-use tokio::sync::Mutex;
 use tracing::*;
 
 mod sync;
@@ -71,11 +70,14 @@ pub async fn start_sync(
     config: Config<'static>,
 ) -> Result<(), Box<dyn Error>> {
     client.register_event_handler(mrsbfh::sync::autojoin).await;
+    client
+        .register_event_handler(mrsbfh::commands::cancel_redacted)
+        .await;
 
-    let config = Arc::new(Mutex::new(config));
+    let config = Arc::new(config);
     client
-        .register_event_handler(move |ev, room, client| {
-            sync::on_room_message(ev, room, client, config.clone())
+        .register_event_handler(move |ev, room, client, raw| {
+            sync::on_room_message(ev, room, client, raw, config.clone())
         })
         .await;
 
@@ -0,0 +1,328 @@
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+
+/// Types which are taken from the `CommandContext` using `FromContext` instead of being arguments
+const EXTRACTORS: &[&str] = &[
+    "CommandContext",
+    "Client",
+    "Joined",
+    "Arc",
+    "Responder",
+    "Sender",
+    "SenderId",
+    "TriggerEventId",
+    "Body",
+    "FormattedBody",
+    "ThreadRoot",
+    "CancellationToken",
+];
+
+enum ArgKind<'a> {
+    Required(&'a syn::Type),
+    Optional(&'a syn::Type),
+    Rest(&'a syn::Type),
+    RestString,
+}
+
+struct TypedArg<'a> {
+    name: String,
+    kind: ArgKind<'a>,
+}
+
+/// Returns the inner type if `ty` is `wrapper<inner>`
+fn generic_inner<'a>(ty: &'a syn::Type, wrapper: &str) -> Option<&'a syn::Type> {
+    if let syn::Type::Path(path) = ty {
+        let segment = path.path.segments.last()?;
+        if segment.ident != wrapper {
+            return None;
+        }
+        if let syn::PathArguments::AngleBracketed(ref generics) = segment.arguments {
+            if generics.args.len() == 1 {
+                if let Some(syn::GenericArgument::Type(inner)) = generics.args.first() {
+                    return Some(inner);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The last segment of a type path like `Client` for `matrix_sdk::Client`
+fn last_segment(ty: &syn::Type) -> Option<&syn::PathSegment> {
+    match ty {
+        syn::Type::Path(path) => path.path.segments.last(),
+        _ => None,
+    }
+}
+
+fn is_extractor(ty: &syn::Type) -> bool {
+    last_segment(ty).is_some_and(|segment| EXTRACTORS.iter().any(|name| segment.ident == name))
+}
+
+/// The state type of the command if it takes the state or the whole context
+fn state_type(params: &[&syn::PatType]) -> Option<syn::Type> {
+    params.iter().find_map(|param| {
+        generic_inner(&param.ty, "Arc")
+            .or_else(|| generic_inner(&param.ty, "CommandContext"))
+            .cloned()
+    })
+}
+
+/// Checks for the old `(client, tx, config, sender: String, room_id: RoomId, ...)` signature
+fn is_legacy(params: &[&syn::PatType]) -> bool {
+    params.len() >= 5
+        && is_type(&params[0].ty, "Client")
+        && is_type(&params[3].ty, "String")
+        && is_type(&params[4].ty, "RoomId")
+}
+
+fn is_type(ty: &syn::Type, name: &str) -> bool {
+    if let syn::Type::Path(path) = ty {
+        if let Some(segment) = path.path.segments.last() {
+            return segment.ident == name && segment.arguments.is_empty();
+        }
+    }
+    false
+}
+
+/// Checks for the classic `args: Vec<&str>` parameter
+fn is_raw_args(ty: &syn::Type) -> bool {
+    if let Some(syn::Type::Reference(reference)) = generic_inner(ty, "Vec") {
+        return is_type(&reference.elem, "str");
+    }
+    false
+}
+
+fn arg_name(pat: &syn::Pat) -> syn::Result<String> {
+    if let syn::Pat::Ident(ident) = pat {
+        let name = ident.ident.to_string();
+        Ok(name
+            .trim_start_matches("r#")
+            .trim_start_matches('_')
+            .to_string())
+    } else {
+        Err(syn::Error::new(
+            pat.span(),
+            "command arguments need to be simple identifiers",
+        ))
+    }
+}
+
+fn typed_args<'a>(params: &[&'a syn::PatType]) -> syn::Result<Vec<TypedArg<'a>>> {
+    let mut args = Vec::with_capacity(params.len());
+    let mut seen_optional = false;
+    for (i, param) in params.iter().enumerate() {
+        let is_last = i == params.len() - 1;
+        let kind = if let Some(inner) = generic_inner(&param.ty, "Option") {
+            seen_optional = true;
+            ArgKind::Optional(inner)
+        } else if let Some(inner) = generic_inner(&param.ty, "Vec") {
+            if !is_last {
+                return Err(syn::Error::new(
+                    param.ty.span(),
+                    "only the last argument of a command can be a `Vec`",
+                ));
+            }
+            ArgKind::Rest(inner)
+        } else if is_last && is_type(&param.ty, "String") {
+            if seen_optional {
+                return Err(syn::Error::new(
+                    param.ty.span(),
+                    "a rest of line `String` can't follow an optional argument",
+                ));
+            }
+            ArgKind::RestString
+        } else {
+            if seen_optional {
+                return Err(syn::Error::new(
+                    param.ty.span(),
+                    "required arguments can't follow optional ones",
+                ));
+            }
+            ArgKind::Required(&param.ty)
+        };
+        args.push(TypedArg {
+            name: arg_name(&param.pat)?,
+            kind,
+        });
+    }
+    Ok(args)
+}
+
+fn generate_usage(command: &str, args: &[TypedArg<'_>]) -> String {
+    let mut usage = command.to_string();
+    for arg in args {
+        let part = match arg.kind {
+            ArgKind::Required(_) => format!(" <{}>", arg.name),
+            ArgKind::Optional(_) => format!(" [{}]", arg.name),
+            ArgKind::Rest(_) => format!(" [{}...]", arg.name),
+            ArgKind::RestString => format!(" <{}...>", arg.name),
+        };
+        usage.push_str(&part);
+    }
+    usage
+}
+
+/// Wraps a command into a function taking the `CommandContext` which `command_generate` calls
+///
+/// The parameters of the command are either taken from the context if their type is one of the
+/// [EXTRACTORS] or parsed from the arguments. The old positional signature starting with
+/// `client, tx, config, sender, room_id` keeps working. The returned usage is either the explicit
+/// `usage` of the command or the one generated from the arguments.
+///
+/// The wrapper returns `None` without calling the command if the arguments can't be parsed.
+///
+/// With `reply` all messages of the command are sent as replies.
+pub(crate) fn wrap_command(
+    input: &syn::ItemFn,
+    usage: Option<String>,
+    reply: bool,
+) -> syn::Result<(proc_macro2::TokenStream, Option<String>)> {
+    let mut params = Vec::with_capacity(input.sig.inputs.len());
+    for param in input.sig.inputs.iter() {
+        match param {
+            syn::FnArg::Typed(param) => params.push(param),
+            syn::FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new(
+                    receiver.span(),
+                    "commands can't take `self`",
+                ))
+            }
+        }
+    }
+
+    // Each parameter is turned into an expression which is passed to the original function
+    let legacy = is_legacy(&params);
+    let mut call_args = Vec::with_capacity(params.len());
+    let mut extractions = Vec::new();
+    let mut arg_params = Vec::new();
+    let mut arg_idents = Vec::new();
+    let mut raw_args = false;
+    for (i, param) in params.iter().enumerate() {
+        let ident = format_ident!("__param_{}", i);
+        let ty = &param.ty;
+        if legacy && i == 3 {
+            extractions.push(quote! { let #ident = ctx.sender.to_string(); });
+        } else if legacy && i == 4 {
+            extractions.push(quote! { let #ident = ctx.room.room_id().clone(); });
+        } else if is_extractor(ty) {
+            extractions.push(quote! {
+                let #ident = <#ty as mrsbfh::commands::FromContext<_>>::from_context(&ctx);
+            });
+        } else if is_raw_args(ty) {
+            raw_args = true;
+            extractions.push(quote! {
+                let #ident: Vec<&str> = __args.iter().map(String::as_str).collect();
+            });
+        } else {
+            arg_params.push(*param);
+            arg_idents.push(ident.clone());
+        }
+        call_args.push(ident);
+    }
+    if raw_args && !arg_params.is_empty() {
+        return Err(syn::Error::new(
+            input.sig.inputs.span(),
+            "a command can either take the raw `args: Vec<&str>` or typed arguments",
+        ));
+    }
+
+    let args = typed_args(&arg_params)?;
+    let command = input.sig.ident.to_string();
+    let usage = if raw_args {
+        usage
+    } else {
+        Some(usage.unwrap_or_else(|| generate_usage(command.trim_start_matches("r#"), &args)))
+    };
+
+    let parse_calls = args.iter().zip(arg_idents.iter()).map(|(arg, ident)| {
+        let name = &arg.name;
+        let call = match arg.kind {
+            ArgKind::Required(ty) => quote! { parser.required::<#ty>(#name) },
+            ArgKind::Optional(ty) => quote! { parser.optional::<#ty>(#name) },
+            ArgKind::Rest(ty) => quote! { parser.rest::<#ty>(#name) },
+            ArgKind::RestString => quote! { parser.rest_string(#name) },
+        };
+        quote! {
+            let #ident = match #call {
+                Ok(v) => v,
+                Err(e) => {
+                    mrsbfh::commands::send_usage(&ctx.responder, #usage, &e).await;
+                    return None;
+                }
+            };
+        }
+    });
+    let parsing = if raw_args {
+        quote! {
+            let __args = match ctx.args() {
+                Ok(args) => args,
+                Err(e) => {
+                    mrsbfh::commands::send_markdown_notice(&ctx.responder, &e.to_string()).await;
+                    return None;
+                }
+            };
+        }
+    } else {
+        let finish = match args.last().map(|arg| &arg.kind) {
+            Some(ArgKind::Rest(_)) | Some(ArgKind::RestString) => quote! {},
+            _ => quote! {
+                if let Err(e) = parser.finish() {
+                    mrsbfh::commands::send_usage(&ctx.responder, #usage, &e).await;
+                    return None;
+                }
+            },
+        };
+        quote! {
+            #[allow(unused_mut)]
+            let mut parser = mrsbfh::commands::ArgParser::new(&ctx.raw_args);
+            #(#parse_calls)*
+            #finish
+        }
+    };
+
+    let reply = if reply {
+        quote! {
+            let mut ctx = ctx;
+            ctx.responder.set_reply_by_default(true);
+        }
+    } else {
+        quote! {}
+    };
+
+    let mut generics = input.sig.generics.clone();
+    let state = match state_type(&params) {
+        Some(state) => state,
+        None => {
+            generics.params.push(syn::parse_quote! { __S });
+            syn::parse_quote! { __S }
+        }
+    };
+    let where_clause = &generics.where_clause;
+
+    let attrs = &input.attrs;
+    let vis = &input.vis;
+    let ident = &input.sig.ident;
+    let output = match input.sig.output {
+        syn::ReturnType::Default => quote! { -> Option<()> },
+        syn::ReturnType::Type(_, ref ty) => quote! { -> Option<#ty> },
+    };
+
+    let mut inner = input.clone();
+    inner.attrs.clear();
+    inner.vis = syn::Visibility::Inherited;
+    inner.sig.ident = format_ident!("inner");
+
+    let wrapped = quote! {
+        #(#attrs)*
+        #vis async fn #ident #generics(ctx: mrsbfh::commands::CommandContext<#state>) #output #where_clause {
+            #inner
+
+            #reply
+            #parsing
+            #(#extractions)*
+            Some(inner(#(#call_args,)*).await)
+        }
+    };
+    Ok((wrapped, usage))
+}
@@ -0,0 +1,269 @@
+use convert_case::{Case, Casing};
+use quote::quote;
+use syn::spanned::Spanned;
+
+/// A variant of the `Commands` enum which is either a command or a nested group
+pub(crate) struct CommandVariant {
+    /// The name used to invoke it
+    ///
+    /// For commands this is the primary name (`NAMES[0]`), the snake case name of the function.
+    pub(crate) name: String,
+    /// The lowercased variant name of commands if it differs from their primary name
+    ///
+    /// Earlier versions dispatched `HelloWorld` as `helloworld`, so it is still accepted as an
+    /// implicit alias.
+    pub(crate) legacy_name: Option<String>,
+    /// The snake case name of the submodule
+    pub(crate) snake: String,
+    /// The submodule which contains the command or group
+    pub(crate) module: syn::Ident,
+    pub(crate) span: proc_macro2::Span,
+    pub(crate) is_group: bool,
+}
+
+impl CommandVariant {
+    pub(crate) fn from_enum(input: &syn::ItemEnum) -> Vec<CommandVariant> {
+        input
+            .variants
+            .iter()
+            .map(|v| {
+                let snake = v.ident.to_string().to_case(Case::Snake);
+                let lowercase = v.ident.to_string().to_lowercase();
+                let is_group = v.attrs.iter().any(|attr| attr.path.is_ident("group"));
+                let (name, legacy_name) = if is_group {
+                    (lowercase, None)
+                } else if lowercase == snake {
+                    (snake.clone(), None)
+                } else {
+                    (snake.clone(), Some(lowercase))
+                };
+                CommandVariant {
+                    name,
+                    legacy_name,
+                    snake: snake.clone(),
+                    module: quote::format_ident!("r#{}", syn::Ident::new(&snake, v.span())),
+                    span: v.span(),
+                    is_group,
+                }
+            })
+            .collect()
+    }
+
+    fn help_const(&self) -> syn::Ident {
+        syn::Ident::new(&format!("{}_HELP", self.snake.to_uppercase()), self.span)
+    }
+
+    fn details_const(&self) -> syn::Ident {
+        syn::Ident::new(&format!("{}_DETAILS", self.snake.to_uppercase()), self.span)
+    }
+
+    fn permissions_const(&self) -> syn::Ident {
+        syn::Ident::new(
+            &format!("{}_PERMISSIONS", self.snake.to_uppercase()),
+            self.span,
+        )
+    }
+
+    fn cooldown_const(&self) -> syn::Ident {
+        syn::Ident::new(
+            &format!("{}_COOLDOWN", self.snake.to_uppercase()),
+            self.span,
+        )
+    }
+
+    fn timeout_const(&self) -> syn::Ident {
+        syn::Ident::new(&format!("{}_TIMEOUT", self.snake.to_uppercase()), self.span)
+    }
+
+    fn names_const(&self) -> syn::Ident {
+        syn::Ident::new(&format!("{}_NAMES", self.snake.to_uppercase()), self.span)
+    }
+
+    /// An expression for all names the variant can be invoked with
+    fn names(&self) -> proc_macro2::TokenStream {
+        if self.is_group {
+            let name = &self.name;
+            quote! { &[#name] }
+        } else {
+            let module = &self.module;
+            let names_const = self.names_const();
+            quote! { #module::#names_const }
+        }
+    }
+}
+
+/// Derives the short form of a command from the first letters of each word
+pub(crate) fn short_form(name: &str) -> String {
+    name.to_case(Case::Snake)
+        .split('_')
+        .filter_map(|x| x.chars().next())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Const assertions which fail the build if any names, aliases or short forms collide
+///
+/// The names of the commands are only known to the `#[command]` macro in the submodules which is
+/// why this can't be checked by the macro itself.
+pub(crate) fn collision_checks(
+    variants: &[CommandVariant],
+    with_help: bool,
+) -> proc_macro2::TokenStream {
+    let mut checks = Vec::new();
+    for (i, a) in variants.iter().enumerate() {
+        let a_names = a.names();
+        let message = format!(
+            "The command `{}` uses the same name, alias or short form twice",
+            a.name
+        );
+        checks.push(quote! {
+            if mrsbfh::commands::command_utils::has_duplicate_names(#a_names) {
+                panic!(#message);
+            }
+        });
+        if with_help {
+            let message = format!(
+                "The command `{}` collides with the built-in `help` or `h`",
+                a.name
+            );
+            checks.push(quote! {
+                if mrsbfh::commands::command_utils::names_collide(#a_names, &["help", "h"]) {
+                    panic!(#message);
+                }
+            });
+        }
+        for b in variants.iter().skip(i + 1) {
+            let b_names = b.names();
+            let message = format!(
+                "The commands `{}` and `{}` share a name, alias or short form",
+                a.name, b.name
+            );
+            checks.push(quote! {
+                if mrsbfh::commands::command_utils::names_collide(#a_names, #b_names) {
+                    panic!(#message);
+                }
+            });
+        }
+        if let Some(legacy_name) = &a.legacy_name {
+            for b in variants.iter().filter(|b| b.name != a.name) {
+                let b_names = b.names();
+                let message = format!(
+                    "The command `{}` can also be invoked as `{}` which is a name, alias or short form of `{}`",
+                    a.name, legacy_name, b.name
+                );
+                checks.push(quote! {
+                    if mrsbfh::commands::command_utils::names_collide(&[#legacy_name], #b_names) {
+                        panic!(#message);
+                    }
+                });
+            }
+        }
+    }
+    quote! {
+        const _: () = {
+            #(#checks)*
+        };
+    }
+}
+
+/// The names and aliases of all variants grouped by variant
+pub(crate) fn all_names(variants: &[CommandVariant]) -> Vec<proc_macro2::TokenStream> {
+    variants.iter().map(CommandVariant::names).collect()
+}
+
+/// The match arms which call the commands or hand over to the nested groups
+///
+/// They evaluate to the `UnknownCommand` if a nested group doesn't know the subcommand.
+pub(crate) fn dispatch_arms(variants: &[CommandVariant]) -> proc_macro2::TokenStream {
+    let arms = variants.iter().map(|v| {
+        let name = &v.name;
+        let module = &v.module;
+        if v.is_group {
+            quote! {
+                #name => {
+                    let mut ctx = ctx;
+                    let prefix = format!("{}{} ", mrsbfh::commands::command_utils::current_prefix(), #name);
+                    let (subcommand, rest) = mrsbfh::commands::command_utils::split_first_word(&ctx.raw_args);
+                    if subcommand.is_empty() {
+                        let help_markdown = #module::help_markdown(&prefix, #name, &[]).unwrap_or_default();
+                        mrsbfh::commands::send_help(&ctx.responder, &help_markdown).await;
+                        return None;
+                    }
+                    let subcommand = subcommand.to_lowercase();
+                    ctx.raw_args = rest.to_string();
+                    mrsbfh::commands::command_utils::PREFIX
+                        .scope(prefix, #module::dispatch_command(&subcommand, ctx))
+                        .await
+                },
+            }
+        } else {
+            let names = v.names();
+            let names_const = v.names_const();
+            let permissions_const = v.permissions_const();
+            let cooldown_const = v.cooldown_const();
+            let timeout_const = v.timeout_const();
+            let legacy_name = v.legacy_name.iter();
+            quote! {
+                cmd if #names.contains(&cmd) #(|| cmd == #legacy_name)* => {
+                    mrsbfh::commands::run_command(
+                        ctx,
+                        #module::#names_const[0],
+                        &#module::#permissions_const,
+                        &#module::#cooldown_const,
+                        #module::#timeout_const,
+                        #module::#module,
+                    )
+                    .await;
+                    None
+                },
+            }
+        }
+    });
+    quote! { #(#arms)* }
+}
+
+/// The format string and its arguments to list the commands followed by the sections of the
+/// nested groups
+///
+/// `level` is an expression for the heading level used by the group sections.
+pub(crate) fn help_list(
+    variants: &[CommandVariant],
+    level: proc_macro2::TokenStream,
+) -> (String, Vec<proc_macro2::TokenStream>) {
+    let mut format_string = String::new();
+    let mut parts = Vec::with_capacity(variants.len());
+    for v in variants.iter().filter(|v| !v.is_group) {
+        let module = &v.module;
+        let help_const = v.help_const();
+        format_string.push_str("{}");
+        parts.push(quote! { #module::#help_const });
+    }
+    for v in variants.iter().filter(|v| v.is_group) {
+        let module = &v.module;
+        let name = &v.name;
+        format_string.push_str("\n{}");
+        parts.push(quote! { #module::help_section(#name, #level) });
+    }
+    (format_string, parts)
+}
+
+/// The match arms which look up the detailed help of a command or the help of a nested group
+pub(crate) fn help_path_arms(variants: &[CommandVariant]) -> proc_macro2::TokenStream {
+    let arms = variants.iter().map(|v| {
+        let module = &v.module;
+        let name = &v.name;
+        if v.is_group {
+            quote! {
+                Some(#name) => #module::help_markdown(&format!("{}{} ", prefix, #name), #name, &path[1..]),
+            }
+        } else {
+            let names = v.names();
+            let details_const = v.details_const();
+            let legacy_name = v.legacy_name.iter();
+            quote! {
+                Some(cmd) if #names.contains(&cmd) #(|| cmd == #legacy_name)* => Some(#module::#details_const.to_markdown(prefix)),
+            }
+        }
+    });
+    quote! { #(#arms)* }
+}
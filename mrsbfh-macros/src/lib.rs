@@ -1,6 +1,8 @@
+pub(crate) mod args;
+pub(crate) mod generate;
 pub(crate) mod utils;
-use crate::utils::get_arg;
-use convert_case::{Case, Casing};
+use crate::generate::CommandVariant;
+use crate::utils::MacroArgs;
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse_macro_input;
@@ -9,17 +11,105 @@ use syn::spanned::Spanned;
 /// Used to define a command
 ///
 /// ```compile_fail
-/// use std::sync::Arc;
-/// use tokio::sync::Mutex;
+/// #[command(help = "Description")]
+/// async fn hello_world(responder: Responder, SenderId(sender): SenderId, mut args: Vec<&str>) -> Result<(), Box<dyn std::error::Error>> {}
+/// ```
+///
+/// Parameters like `Client`, `Joined`, `Responder` or the whole `CommandContext` are taken from the
+/// context of the invocation (see `mrsbfh::commands::FromContext`). Instead of `args` the command
+/// can also take typed arguments which get parsed using `mrsbfh::commands::FromArg`:
 ///
+/// ```compile_fail
 /// #[command(help = "Description")]
-/// async fn hello_world(mut tx: mrsbfh::Sender, config: Arc<Mutex<Config>>, sender: String, mut args: Vec<&str>) -> Result<(), Box<dyn std::error::Error>> where Config: mrsbfh::config::Loader + Clone {}
+/// async fn add(responder: Responder, a: i64, b: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {}
 /// ```
+///
+/// `usage`, `long_help` and repeated `example = "..."` fields are shown by `!help <command>`. The
+/// overview only shows the first line of `help`.
+///
+/// `min_power_level = 50` and `role = "admin"` (or a list of roles) restrict who can use the
+/// command. They get checked before the command is called.
+///
+/// `cooldown = "30s"` limits how often the command can be used. It applies `per = "user"` by
+/// default, `"room"` and `"global"` are possible as well.
+///
+/// With `reply = true` all messages of the command are sent as replies to the message which
+/// invoked it.
+///
+/// `timeout = "60s"` stops waiting for the command after that time and tells the user that it
+/// timed out. It replaces the default `timeout` of [macro@command_generate].
+///
+/// Besides its name a command can be invoked using the first letters of each word (`hw` for
+/// `hello_world`) and any additional `aliases = ["hi", "hey"]`. The short form can be disabled
+/// using `short = false`.
 #[proc_macro_attribute]
 pub fn command(args: TokenStream, input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as syn::ItemFn);
 
-    let args = parse_macro_input!(args as syn::AttributeArgs);
+    let args = match MacroArgs::parse(
+        args,
+        &[
+            "help",
+            "aliases",
+            "short",
+            "usage",
+            "long_help",
+            "example",
+            "min_power_level",
+            "role",
+            "cooldown",
+            "per",
+            "reply",
+            "timeout",
+        ],
+        "#[command(help = \"<description>\")]",
+    ) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let command_name = input
+        .sig
+        .ident
+        .to_string()
+        .trim_start_matches("r#")
+        .to_lowercase();
+    let names_const_name = syn::Ident::new(
+        &format!("{}_NAMES", command_name.to_uppercase()),
+        input.sig.span(),
+    );
+    let mut names = vec![command_name.clone()];
+    match args.bool("short") {
+        Ok(Some(false)) => {}
+        Ok(_) => {
+            let short = generate::short_form(&command_name);
+            if short != command_name {
+                names.push(short);
+            }
+        }
+        Err(e) => return e.to_compile_error().into(),
+    }
+    match args.str_list("aliases") {
+        Ok(aliases) => {
+            for alias in aliases.unwrap_or_default() {
+                let valid = !alias.value().is_empty()
+                    && alias
+                        .value()
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                if !valid {
+                    return syn::Error::new(
+                        alias.span(),
+                        "aliases can only contain letters, digits, `_` and `-`",
+                    )
+                    .to_compile_error()
+                    .into();
+                }
+                names.push(alias.value().to_lowercase());
+            }
+        }
+        Err(e) => return e.to_compile_error().into(),
+    }
 
     let help_const_name = syn::Ident::new(
         &format!(
@@ -28,21 +118,122 @@ pub fn command(args: TokenStream, input: TokenStream) -> TokenStream {
         ),
         input.sig.span(),
     );
-    let help_description = match get_arg(
-        input.span(),
-        args,
-        "help",
-        "#[command(help = \"<description>\")]",
-        1,
-    ) {
-        Ok(v) => syn::LitStr::new(&format!("* {}\n", v.value()), v.span()),
-        Err(e) => return e,
+    let help = match args.required_str("help") {
+        Ok(v) => v,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // The overview only lists the first line
+    let help_description = syn::LitStr::new(
+        &format!("* {}\n", help.value().lines().next().unwrap_or_default()),
+        help.span(),
+    );
+    let details_const_name = syn::Ident::new(
+        &format!("{}_DETAILS", command_name.to_uppercase()),
+        input.sig.span(),
+    );
+    let usage = match args.str("usage") {
+        Ok(v) => v.map(|v| v.value()),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let long_help = match args.str("long_help") {
+        Ok(Some(v)) => quote! { Some(#v) },
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let examples = match args.all_str("example") {
+        Ok(v) => v,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let reply = match args.bool("reply") {
+        Ok(v) => v.unwrap_or(false),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let (command, usage) = match args::wrap_command(&input, usage, reply) {
+        Ok(command) => command,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let usage = match usage {
+        Some(usage) => quote! { Some(#usage) },
+        None => quote! { None },
+    };
+    let aliases = &names[1..];
+    let permissions_const_name = syn::Ident::new(
+        &format!("{}_PERMISSIONS", command_name.to_uppercase()),
+        input.sig.span(),
+    );
+    let min_power_level = match args.int("min_power_level") {
+        Ok(Some(v)) => quote! { Some(#v) },
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let roles = match args.str_list("role") {
+        Ok(v) => v.unwrap_or_default(),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let cooldown_const_name = syn::Ident::new(
+        &format!("{}_COOLDOWN", command_name.to_uppercase()),
+        input.sig.span(),
+    );
+    let per = match args.str("per") {
+        Ok(Some(per)) => match per.value().as_str() {
+            "user" => quote! { mrsbfh::ratelimit::CooldownScope::User },
+            "room" => quote! { mrsbfh::ratelimit::CooldownScope::Room },
+            "global" => quote! { mrsbfh::ratelimit::CooldownScope::Global },
+            _ => {
+                return syn::Error::new(
+                    per.span(),
+                    "`per` needs to be \"user\", \"room\" or \"global\"",
+                )
+                .to_compile_error()
+                .into()
+            }
+        },
+        Ok(None) => quote! { mrsbfh::ratelimit::CooldownScope::User },
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let cooldown = match args.duration_millis("cooldown") {
+        Ok(Some(millis)) => quote! {
+            Some(mrsbfh::ratelimit::Cooldown {
+                duration: std::time::Duration::from_millis(#millis),
+                per: #per,
+            })
+        },
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let timeout_const_name = syn::Ident::new(
+        &format!("{}_TIMEOUT", command_name.to_uppercase()),
+        input.sig.span(),
+    );
+    let timeout = match args.duration_millis("timeout") {
+        Ok(Some(millis)) => quote! { Some(std::time::Duration::from_millis(#millis)) },
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
     };
 
     let code = quote! {
-        #input
+        #command
         pub(crate) const #help_const_name: &str = #help_description;
-
+        pub(crate) const #names_const_name: &[&str] = &[#(#names,)*];
+        pub(crate) const #details_const_name: mrsbfh::commands::CommandHelp = mrsbfh::commands::CommandHelp {
+            name: #command_name,
+            summary: #help,
+            usage: #usage,
+            long_help: #long_help,
+            examples: &[#(#examples,)*],
+            aliases: &[#(#aliases,)*],
+        };
+        pub(crate) const #permissions_const_name: mrsbfh::commands::Permissions = mrsbfh::commands::Permissions {
+            min_power_level: #min_power_level,
+            roles: &[#(#roles,)*],
+        };
+        pub(crate) const #cooldown_const_name: mrsbfh::commands::CommandCooldown = mrsbfh::commands::CommandCooldown {
+            key: module_path!(),
+            cooldown: #cooldown,
+        };
+        pub(crate) const #timeout_const_name: Option<std::time::Duration> = #timeout;
     };
     code.into()
 }
@@ -50,84 +241,168 @@ pub fn command(args: TokenStream, input: TokenStream) -> TokenStream {
 /// Used to generate the match case and help text
 ///
 /// ```compile_fail
-/// #[command_generate(bot_name = "botless", description = "Is it a bot or is it not?")]
+/// #[command_generate(
+///     bot_name = "botless",
+///     description = "Is it a bot or is it not?",
+///     state = Config<'static>,
+///     error = Error
+/// )]
 /// enum Commands {
 ///     In,
 ///     Out
 /// }
 /// ```
 ///
+/// `state` is the type shared by all commands and has to implement `mrsbfh::commands::State`. It
+/// can be the config itself, the config in a tokio `Mutex` or `RwLock` or your own type holding
+/// anything else the commands need. Commands get it as `Arc<MyState>`. `error` is the error type
+/// returned by the commands.
+///
+/// The prefix defaults to `!` and can be changed using `prefix = "?"` or a list like
+/// `prefix = ["!", "?bot "]`.
+///
+/// With `mention = true` commands can also be invoked by mentioning the bot like
+/// `ExampleBot: hello_world`.
+///
+/// It is a compile error if two commands share a name, alias or short form or if one of them
+/// collides with `help` or `h`.
+///
+/// `room_budget = "20/1m"` limits how many commands each room can use.
+///
+/// `timeout = "5m"` is the default timeout of all commands, see [macro@command].
+///
+/// Unknown commands are ignored by default. With `suggestions = true` the bot replies with the
+/// closest commands instead and `fallback = path::to::handler` calls your own handler with the
+/// signature `(cmd: &str, names: &[&[&str]], ctx: CommandContext<MyState>)`. `names` are the
+/// commands of the group the unknown one was used in.
+///
+/// Variants marked with `#[group]` point at a submodule which uses [macro@command_group] and
+/// hand over all arguments to it.
+///
 /// **Note**: The defined enum will NOT be present at runtime. It gets replaced fully
 #[proc_macro_attribute]
 pub fn command_generate(args: TokenStream, input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as syn::ItemEnum);
 
-    let args = parse_macro_input!(args as syn::AttributeArgs);
-
-    let commands = input.variants.iter().map(|v| {
-        let command_string = v.ident.to_string().to_lowercase();
-        let command_short = {
-            let chars: Vec<String> = v
-                .ident
-                .to_string()
-                .to_case(Case::Snake)
-                .split('_')
-                .map(|x| x.chars().next().unwrap().to_string().to_lowercase())
-                .collect();
-            chars.join("")
-        };
-        let command = quote::format_ident!(
-            "r#{}",
-            syn::Ident::new(&v.ident.to_string().to_case(Case::Snake), v.span())
-        );
-
-        quote! {
-            #command_string => {
-                #command::#command(client, tx, config, sender, room_id, args).await
-            },
-            #command_short => {
-                #command::#command(client, tx, config, sender, room_id, args).await
-            },
-        }
-    });
+    let args = match MacroArgs::parse(
+        args,
+        &[
+            "bot_name",
+            "description",
+            "prefix",
+            "mention",
+            "suggestions",
+            "fallback",
+            "room_budget",
+            "timeout",
+            "state",
+            "error",
+        ],
+        "#[command_generate(bot_name = \"<bot name>\", description = \"<bot description>\", state = <state type>, error = <error type>)]",
+    ) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
 
-    let help_parts = input.variants.iter().map(|v| {
-        let command_string = v.ident.to_string().to_case(Case::Snake);
-        let help_command =
-            syn::Ident::new(&format!("{}_HELP", command_string.to_uppercase()), v.span());
-        let command = quote::format_ident!(
-            "r#{}",
-            syn::Ident::new(&v.ident.to_string().to_case(Case::Snake), v.span())
-        );
+    let variants = CommandVariant::from_enum(&input);
+    let commands = generate::dispatch_arms(&variants);
+    let (help_list_format, help_parts) = generate::help_list(&variants, quote! {3});
+    let help_format_string = format!("{{}}{}", help_list_format);
+    let help_path_arms = generate::help_path_arms(&variants);
+    let collision_checks = generate::collision_checks(&variants, true);
 
+    let bot_name = match args.required_str("bot_name") {
+        Ok(v) => v.value(),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let description = match args.required_str("description") {
+        Ok(v) => format!("{}\n\n", v.value()),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let state = match args.required_type("state") {
+        Ok(v) => v,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let error = match args.required_type("error") {
+        Ok(v) => v,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let prefixes = match args.str_list("prefix") {
+        Ok(Some(v)) => v,
+        Ok(None) => vec![syn::LitStr::new("!", input.span())],
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if let Some(empty) = prefixes.iter().find(|prefix| prefix.value().is_empty()) {
+        return syn::Error::new(empty.span(), "the prefix can't be empty")
+            .to_compile_error()
+            .into();
+    }
+    let mention = match args.bool("mention") {
+        Ok(v) => v.unwrap_or(false),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let mention_lookup = if mention {
         quote! {
-            #command::#help_command
+            if command.is_none() {
+                command = mrsbfh::commands::command_utils::resolve_mention_command(&ctx.client, ctx.room.room_id(), &ctx.body, ctx.formatted_body.as_deref(), &prefixes).await;
+            }
         }
-    });
-    let mut help_format_string = String::from("{}");
-    input.variants.iter().for_each(|_| {
-        help_format_string = format!("{}{}", help_format_string, "{}");
-    });
+    } else {
+        quote! {}
+    };
 
-    let bot_name = match get_arg(
-        input.span(),
-        args.clone(),
-        "bot_name",
-        "#[command_generate(bot_name = \"<bot name>\", description = \"<bot description>\")]",
-        2,
-    ) {
-        Ok(v) => v.value(),
-        Err(e) => return e,
+    let suggestions = match args.bool("suggestions") {
+        Ok(v) => v.unwrap_or(false),
+        Err(e) => return e.to_compile_error().into(),
     };
-    let description = match get_arg(
-        input.span(),
-        args,
-        "description",
-        "#[command_generate(bot_name = \"<bot name>\", description = \"<bot description>\")]",
-        2,
-    ) {
-        Ok(v) => format!("{}\n\n", v.value()),
-        Err(e) => return e,
+    let fallback = match args.path("fallback") {
+        Ok(Some(fallback)) => quote! {
+            #fallback(cmd, names, ctx).await
+        },
+        Ok(None) if suggestions => quote! {
+            mrsbfh::commands::send_suggestions(&ctx.responder, cmd, names).await;
+            Ok(())
+        },
+        Ok(None) => quote! {
+            let _ = (cmd, names, ctx);
+            Ok(())
+        },
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let all_names = generate::all_names(&variants);
+    let room_budget = match args.str("room_budget") {
+        Ok(Some(budget)) => {
+            let parsed = budget.value().split_once('/').and_then(|(commands, per)| {
+                Some((
+                    commands.trim().parse::<u32>().ok()?,
+                    utils::parse_duration_millis(per)?,
+                ))
+            });
+            match parsed {
+                Some((commands, millis)) => quote! {
+                    Some(mrsbfh::ratelimit::Budget {
+                        commands: #commands,
+                        per: std::time::Duration::from_millis(#millis),
+                    })
+                },
+                None => {
+                    return syn::Error::new(
+                        budget.span(),
+                        "`room_budget` needs to look like \"20/1m\" for 20 commands per minute",
+                    )
+                    .to_compile_error()
+                    .into()
+                }
+            }
+        }
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let default_timeout = match args.duration_millis("timeout") {
+        Ok(Some(millis)) => quote! { Some(std::time::Duration::from_millis(#millis)) },
+        Ok(None) => quote! { None },
+        Err(e) => return e.to_compile_error().into(),
     };
 
     let help_title = format!("# Help for the {} Bot\n\n", bot_name);
@@ -135,43 +410,137 @@ pub fn command_generate(args: TokenStream, input: TokenStream) -> TokenStream {
     let help_preamble = help_title + &description + commands_title;
 
     let code = quote! {
+        #collision_checks
+
+        /// The names and aliases of all commands including `help`
+        #[allow(dead_code)]
+        pub(crate) const COMMAND_NAMES: &[&[&str]] = &[#(#all_names,)* &["help", "h"]];
+
+        fn help_markdown(prefix: &str, path: &[String]) -> Option<String> {
+            match path.first().map(|p| p.to_lowercase()).as_deref() {
+                None => Some(format!(
+                    "{}\nUse `{}help <command>` to show the details of a command.\n",
+                    format!(#help_format_string, #help_preamble, #(#help_parts,)*),
+                    prefix,
+                )),
+                #help_path_arms
+                _ => None,
+            }
+        }
 
-        async fn help(
-            mut tx: mrsbfh::Sender,
-        ) -> Result<(), Error> {
-            let options = mrsbfh::pulldown_cmark::Options::empty();
-            let help_markdown = format!(#help_format_string, #help_preamble, #(#help_parts,)*);
-            let parser = mrsbfh::pulldown_cmark::Parser::new_ext(&help_markdown, options);
-            let mut html = String::new();
-            mrsbfh::pulldown_cmark::html::push_html(&mut html, parser);
-            let owned_html = html.to_owned();
-
-            mrsbfh::tokio::spawn(async move {
-                let content = matrix_sdk::ruma::events::AnyMessageEventContent::RoomMessage(
-                    matrix_sdk::ruma::events::room::message::MessageEventContent::notice_html(
-                        &help_markdown,
-                        owned_html,
-                    ),
-                );
+        async fn help(ctx: mrsbfh::commands::CommandContext<#state>) -> Result<(), #error> {
+            let prefix = mrsbfh::commands::command_utils::current_prefix();
+            let path = match ctx.args() {
+                Ok(path) => path,
+                Err(e) => {
+                    mrsbfh::commands::send_markdown_notice(&ctx.responder, &e.to_string()).await;
+                    return Ok(());
+                }
+            };
+            match help_markdown(&prefix, &path) {
+                Some(help_markdown) => mrsbfh::commands::send_help(&ctx.responder, &help_markdown).await,
+                None => mrsbfh::commands::send_help_not_found(&ctx.responder, &path).await,
+            }
 
-                if let Err(e) = tx.send(content).await {
-                    mrsbfh::tracing::error!("Error: {}",e);
-                };
-            });
+            Ok(())
+        }
 
+        /// Parses the message of the context and runs the command it contains
+        ///
+        /// Errors of the command get reported to the user as decided by the
+        /// `mrsbfh::errors::CommandError` implementation of the error type. Panics of the command
+        /// are caught and reported like errors.
+        pub async fn match_command(mut ctx: mrsbfh::commands::CommandContext<#state>) -> Result<(), #error> {
+            // Fetched once so the checks of the command don't have to lock the state again
+            ctx.commands_config = mrsbfh::commands::State::commands_config(&*ctx.state)
+                .await
+                .map(std::sync::Arc::new);
+            let (prefixes, thread_threshold, rerun_edits, default_timeout, processed_events_path) =
+                match ctx.commands_config.as_deref() {
+                    Some(c) => (
+                        c.prefixes.clone(),
+                        c.thread_threshold,
+                        c.rerun_edits,
+                        c.default_timeout,
+                        c.processed_events_path.clone(),
+                    ),
+                    None => (None, None, false, None, None),
+                };
+            ctx.default_timeout = default_timeout.or(#default_timeout);
+            ctx.room_budget = #room_budget;
+            if ctx.is_edit && !rerun_edits {
+                return Ok(());
+            }
+            ctx.responder.set_thread_threshold(thread_threshold);
+            let prefixes = prefixes.unwrap_or_else(|| vec![#(String::from(#prefixes),)*]);
+
+            let mut command = mrsbfh::commands::command_utils::parse_command(&ctx.body, &prefixes);
+            #mention_lookup
+            let command = match command {
+                Some(command) => command,
+                None => return Ok(()),
+            };
+            if !ctx.edit_by_original_sender().await {
+                return Ok(());
+            }
+            if let Some(ref path) = processed_events_path {
+                let event_id = ctx.edit_id.as_ref().unwrap_or(&ctx.event_id);
+                if !mrsbfh::sync::mark_processed(path, event_id).await {
+                    mrsbfh::tracing::debug!("Ignoring {} as it was processed before", event_id);
+                    return Ok(());
+                }
+            }
+            mrsbfh::tracing::info!("Got command: {}", command.name);
+            ctx.fetch_thread_root().await;
+
+            ctx.raw_args = command.rest;
+            let unknown = mrsbfh::commands::command_utils::PREFIX
+                .scope(command.prefix, dispatch_command(command.name.as_str(), ctx))
+                .await;
+            if let Some(mrsbfh::commands::UnknownCommand { name, names, prefix, ctx }) = unknown {
+                // Unknown commands don't hold up the commands after them
+                ctx.dispatch_slot.release();
+                let responder = ctx.responder.clone();
+                let result = mrsbfh::commands::command_utils::PREFIX
+                    .scope(
+                        prefix,
+                        mrsbfh::commands::catch_panic(unknown_command(&name, names, ctx)),
+                    )
+                    .await;
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(ref e)) => mrsbfh::commands::report_error(&responder, &name, e).await,
+                    Err(ref panic) => mrsbfh::commands::report_error(&responder, &name, panic).await,
+                }
+            }
             Ok(())
         }
 
-        pub async fn match_command<'a>(cmd: &str, client: matrix_sdk::Client, config: std::sync::Arc<tokio::sync::Mutex<Config<'a>>>, tx: mrsbfh::Sender, sender: String, room_id: matrix_sdk::ruma::RoomId, args: Vec<&str>,) -> Result<(), Error> where Config<'a>: mrsbfh::config::Loader + Clone {
+        /// Handles a command which doesn't exist, `names` are the ones of the group it was used in
+        async fn unknown_command(
+            cmd: &str,
+            names: &'static [&'static [&'static str]],
+            ctx: mrsbfh::commands::CommandContext<#state>,
+        ) -> Result<(), #error> {
+            #fallback
+        }
+
+        async fn dispatch_command(cmd: &str, ctx: mrsbfh::commands::CommandContext<#state>) -> Option<mrsbfh::commands::UnknownCommand<#state>> {
             match cmd {
-                #(#commands)*
-                "help" => {
-                    help(tx).await
+                #commands
+                "help" | "h" => {
+                    mrsbfh::commands::run_command(
+                        ctx,
+                        "help",
+                        &mrsbfh::commands::Permissions::default(),
+                        &mrsbfh::commands::CommandCooldown { key: "help", cooldown: None },
+                        None,
+                        |ctx| async move { Some(help(ctx).await) },
+                    )
+                    .await;
+                    None
                 },
-                "h" => {
-                    help(tx).await
-                },
-                _ => {Ok(())}
+                _ => Some(mrsbfh::commands::UnknownCommand::new(cmd, COMMAND_NAMES, ctx)),
             }
         }
 
@@ -179,12 +548,125 @@ pub fn command_generate(args: TokenStream, input: TokenStream) -> TokenStream {
     code.into()
 }
 
+/// Used to generate the match case and help text of a group of subcommands
+///
+/// The group is a submodule of the module using `command_generate` (or another group) and contains
+/// the subcommands like any other commands module. It gets invoked like `!room list`.
+///
+/// ```compile_fail
+/// // commands/mod.rs
+/// #[command_generate(bot_name = "Admin", description = "Manages things", state = MyState, error = Error)]
+/// enum Commands {
+///     #[group]
+///     Room,
+/// }
+///
+/// // commands/room/mod.rs
+/// #[command_group(description = "Manage the rooms of the bot", state = MyState)]
+/// enum Commands {
+///     List,
+///     Join,
+///     Leave,
+/// }
+/// ```
+///
+/// `!help room` only lists the subcommands of the group.
+///
+/// **Note**: The defined enum will NOT be present at runtime. It gets replaced fully
+#[proc_macro_attribute]
+pub fn command_group(args: TokenStream, input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::ItemEnum);
+
+    let args = match MacroArgs::parse(
+        args,
+        &["description", "state"],
+        "#[command_group(description = \"<group description>\", state = <state type>)]",
+    ) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let description = match args.required_str("description") {
+        Ok(v) => v.value(),
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let state = match args.required_type("state") {
+        Ok(v) => v,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let variants = CommandVariant::from_enum(&input);
+    let commands = generate::dispatch_arms(&variants);
+    let (help_list_format, help_parts) = generate::help_list(&variants, quote! {level + 1});
+    let help_format_string = format!("{{}} {{}}\n\n{{}}\n\n{}", help_list_format);
+    let help_path_arms = generate::help_path_arms(&variants);
+    let collision_checks = generate::collision_checks(&variants, false);
+    let all_names = generate::all_names(&variants);
+
+    let code = quote! {
+        #collision_checks
+
+        /// The names and aliases of all subcommands
+        pub(crate) const COMMAND_NAMES: &[&[&str]] = &[#(#all_names,)*];
+
+        pub(crate) fn help_section(name: &str, level: usize) -> String {
+            format!(#help_format_string, "#".repeat(level), name, #description, #(#help_parts,)*)
+        }
+
+        /// `prefix` is the one of the subcommands like `!room `
+        pub(crate) fn help_markdown(prefix: &str, name: &str, path: &[String]) -> Option<String> {
+            match path.first().map(|p| p.to_lowercase()).as_deref() {
+                None => Some(help_section(name, 1)),
+                #help_path_arms
+                _ => None,
+            }
+        }
+
+        pub(crate) async fn dispatch_command(cmd: &str, ctx: mrsbfh::commands::CommandContext<#state>) -> Option<mrsbfh::commands::UnknownCommand<#state>> {
+            match cmd {
+                #commands
+                _ => Some(mrsbfh::commands::UnknownCommand::new(cmd, COMMAND_NAMES, ctx)),
+            }
+        }
+    };
+    code.into()
+}
+
 #[proc_macro_derive(ConfigDerive)]
 pub fn config_derive(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as syn::DeriveInput);
     let name = &ast.ident;
 
+    // A field of the type `CommandsConfig` holds the runtime settings for the commands
+    let commands_config_field = match ast.data {
+        syn::Data::Struct(ref data) => data.fields.iter().find(|field| {
+            if let syn::Type::Path(ref path) = field.ty {
+                path.path
+                    .segments
+                    .last()
+                    .is_some_and(|segment| segment.ident == "CommandsConfig")
+            } else {
+                false
+            }
+        }),
+        _ => None,
+    };
+    let commands_config = commands_config_field
+        .and_then(|field| field.ident.as_ref())
+        .map(|field| {
+            quote! {
+                fn commands_config(&self) -> Option<&mrsbfh::config::CommandsConfig> {
+                    Some(&self.#field)
+                }
+            }
+        });
+
     let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let mut state_generics = ast.generics.clone();
+    state_generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote! { Self: Send + Sync + 'static });
+    let state_where_clause = &state_generics.where_clause;
     let expanded = quote! {
         impl #impl_generics mrsbfh::config::Loader for #name #ty_generics #where_clause {
             fn load<P: AsRef<std::path::Path> + std::fmt::Debug>(path: P) -> Result<Self, mrsbfh::errors::ConfigError> {
@@ -192,6 +674,16 @@ pub fn config_derive(input: TokenStream) -> TokenStream {
                 let config: Self = mrsbfh::serde_yaml::from_str(&contents)?;
                 Ok(config)
             }
+
+            #commands_config
+        }
+
+        // Lets the config be the state of the commands on its own
+        #[mrsbfh::async_trait::async_trait]
+        impl #impl_generics mrsbfh::commands::State for #name #ty_generics #state_where_clause {
+            async fn commands_config(&self) -> Option<mrsbfh::config::CommandsConfig> {
+                mrsbfh::config::Loader::commands_config(self).cloned()
+            }
         }
     };
 
@@ -212,85 +704,79 @@ pub fn config_derive(input: TokenStream) -> TokenStream {
 /// use crate::commands::match_command;
 ///
 /// #[mrsbfh::commands::commands]
-/// async fn on_room_message(event: SyncMessageEvent<MessageEventContent>, room: Room) {
+/// async fn on_room_message(
+///     event: SyncMessageEvent<MessageEventContent>,
+///     room: Room,
+///     client: Client,
+///     state: Arc<MyState>,
+/// ) {
 ///         // Your own logic. (Executed BEFORE the commands matching)
 /// }
 /// ```
 ///
+/// The state gets passed to the commands. Its parameter is named `state` or `config` and its type
+/// is the `state` of [macro@command_generate] in an `Arc`.
+///
 #[proc_macro_attribute]
 pub fn commands(_: TokenStream, input: TokenStream) -> TokenStream {
     let mut method = parse_macro_input!(input as syn::ItemFn);
 
     if method.sig.ident == "on_room_message" {
         let original = method.block.clone();
+        let state = state_param(&method.sig);
+        // Without the raw event `match_command` fetches the thread once it found a command
+        let thread_root = match raw_event_param(&method.sig) {
+            Some(raw) => quote! { ctx.read_thread_root(&#raw.0); },
+            None => quote! {},
+        };
         let new_block = syn::parse_quote! {
             {
+                // Keeps the place of the message in line before anything else can overtake it
+                let reservation = mrsbfh::commands::Reservation::new(room.room_id(), &event.sender);
+
                 #original
 
                 // Command matching logic
                 if let matrix_sdk::room::Room::Joined(room) = room {
-                    let msg_body = if let matrix_sdk::ruma::events::SyncMessageEvent {
-                        content: matrix_sdk::ruma::events::room::message::MessageEventContent {
-                            msgtype: matrix_sdk::ruma::events::room::message::MessageType::Text(matrix_sdk::ruma::events::room::message::TextMessageEventContent { body: msg_body, .. }),
-                            ..
-                        },
-                        ..
-                    } = event
-                    {
-                        msg_body.clone()
-                    } else {
-                        String::new()
-                    };
-                    if msg_body.is_empty() {
+                    let (filter, startup_grace) =
+                        match mrsbfh::commands::State::commands_config(&*#state).await {
+                            Some(c) => (c.filter, c.startup_grace),
+                            None => (Default::default(), None),
+                        };
+                    if !filter.allows(&client, &event).await {
+                        return;
+                    }
+                    let startup_grace = startup_grace.unwrap_or(mrsbfh::sync::DEFAULT_STARTUP_GRACE);
+                    if !mrsbfh::sync::sent_after_startup(&event, startup_grace) {
+                        tracing::debug!("Ignoring {} as it was sent before the start", event.event_id);
                         return;
                     }
 
-                    let sender = event.sender.clone().to_string();
-
-                    let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-                    let room_id = room.room_id().clone();
-
-                    let cloned_config = config.clone();
-                    let cloned_client = client.clone();
-                    tokio::spawn(async move {
-                        let normalized_body = mrsbfh::commands::command_utils::WHITESPACE_DEDUPLICATOR_MAGIC.replace_all(&msg_body, " ");
-                        let mut split = msg_body.split_whitespace();
-
-                        let command_raw = split.next().expect("This is not a command").to_lowercase();
-                        let command = mrsbfh::commands::command_utils::COMMAND_MATCHER_MAGIC.captures(command_raw.as_str())
-                                                           .map_or(String::new(), |caps| {
-                                                                caps.get(1)
-                                                                    .map_or(String::new(),
-                                                                            |m| String::from(m.as_str()))
-                                                           });
-                        if !command.is_empty() {
-                           tracing::info!("Got command: {}", command);
-                        }
-                        // Make sure this is immutable
-                        let args: Vec<&str> = split.collect();
-                        if let Err(e) = match_command(
-                            command.as_str(),
-                            cloned_client.clone(),
-                            cloned_config.clone(),
-                            tx,
-                            sender,
-                            room_id,
-                            args,
-                        )
-                        .await
+                    let (tx, rx) = tokio::sync::mpsc::channel(100);
+                    let mut ctx = match mrsbfh::commands::CommandContext::from_event(
+                        client.clone(),
+                        room.clone(),
+                        &event,
+                        #state.clone(),
+                        mrsbfh::commands::Responder::new(tx),
+                    ) {
+                        Some(ctx) => ctx,
+                        None => return,
+                    };
+                    #thread_root
+
+                    ctx.dispatch_slot.reserve(reservation);
+
+                    let dispatch_slot = ctx.dispatch_slot.clone();
+                    let command = tokio::spawn(async move {
+                        if let Err(e) = match_command(ctx).await
                         {
                             tracing::error!("{}", e);
                         }
 
                     });
 
-                    while let Some(v) = rx.recv().await {
-                        if let Err(e) = room.send(v, None)
-                            .await
-                        {
-                            tracing::error!("{}", e);
-                        }
-                    }
+                    mrsbfh::commands::send_answers(rx, command, dispatch_slot, &client, &room).await;
                 }
             }
         };
@@ -299,3 +785,33 @@ pub fn commands(_: TokenStream, input: TokenStream) -> TokenStream {
 
     TokenStream::from(quote! {#method})
 }
+
+/// The parameter holding the state, named `state` or `config`
+fn state_param(sig: &syn::Signature) -> syn::Ident {
+    let names = sig.inputs.iter().filter_map(|input| match input {
+        syn::FnArg::Typed(syn::PatType { pat, .. }) => match &**pat {
+            syn::Pat::Ident(pat) => Some(pat.ident.clone()),
+            _ => None,
+        },
+        _ => None,
+    });
+    names
+        .filter(|name| name == "state" || name == "config")
+        .min_by_key(|name| name != "state")
+        .unwrap_or_else(|| syn::Ident::new("config", sig.span()))
+}
+
+/// The name of the `RawEvent` parameter of the event handler if it has one
+fn raw_event_param(sig: &syn::Signature) -> Option<syn::Ident> {
+    sig.inputs.iter().find_map(|input| match input {
+        syn::FnArg::Typed(syn::PatType { pat, ty, .. }) => match (&**pat, &**ty) {
+            (syn::Pat::Ident(pat), syn::Type::Path(ty))
+                if ty.path.segments.last()?.ident == "RawEvent" =>
+            {
+                Some(pat.ident.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
@@ -1,63 +1,265 @@
-use proc_macro::TokenStream;
-use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 
-pub(crate) fn get_arg<'a>(
-    input_span: proc_macro2::Span,
-    args: syn::AttributeArgs,
-    arg: &'a str,
-    expected: &'a str,
-    expected_args: usize,
-) -> Result<syn::LitStr, TokenStream> {
-    if args.len() == expected_args {
-        let meta = args
+/// A single `name = value` pair of a macro attribute
+pub(crate) struct MacroArg {
+    pub(crate) name: syn::Ident,
+    pub(crate) value: syn::Expr,
+}
+
+impl Parse for MacroArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<syn::Token![=]>()?;
+        // Types like `Config<'static>` aren't expressions, they are kept as they are
+        let fork = input.fork();
+        let is_expr =
+            fork.parse::<syn::Expr>().is_ok() && (fork.is_empty() || fork.peek(syn::Token![,]));
+        let value = if is_expr {
+            input.parse()?
+        } else {
+            let ty: syn::Type = input.parse()?;
+            syn::Expr::Verbatim(quote::quote! { #ty })
+        };
+        Ok(MacroArg { name, value })
+    }
+}
+
+/// The arguments of a macro attribute like `#[command(help = "...")]`
+pub(crate) struct MacroArgs {
+    args: Vec<MacroArg>,
+    span: proc_macro2::Span,
+    expected: &'static str,
+}
+
+impl MacroArgs {
+    /// Parses the arguments and makes sure only the `known` ones are used
+    pub(crate) fn parse(
+        input: proc_macro::TokenStream,
+        known: &[&str],
+        expected: &'static str,
+    ) -> syn::Result<Self> {
+        let span = proc_macro2::TokenStream::from(input.clone()).span();
+        let args = syn::parse::Parser::parse(
+            Punctuated::<MacroArg, syn::Token![,]>::parse_terminated,
+            input,
+        )?;
+        for arg in args.iter() {
+            if !known.iter().any(|known| arg.name == known) {
+                return Err(syn::Error::new(
+                    arg.name.span(),
+                    format!(
+                        "expected `{}`\n\nUnknown field '{}'. Known fields are: {}",
+                        expected,
+                        arg.name,
+                        known.join(", ")
+                    ),
+                ));
+            }
+        }
+        Ok(MacroArgs {
+            args: args.into_iter().collect(),
+            span,
+            expected,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&MacroArg> {
+        self.args.iter().find(|arg| arg.name == name)
+    }
+
+    fn expected_error(&self, span: proc_macro2::Span, message: String) -> syn::Error {
+        syn::Error::new(span, format!("expected `{}`\n\n{}", self.expected, message))
+    }
+
+    /// Gets a required str literal
+    pub(crate) fn required_str(&self, name: &str) -> syn::Result<syn::LitStr> {
+        self.str(name)?.ok_or_else(|| {
+            self.expected_error(self.span, format!("The field '{}' is required!", name))
+        })
+    }
+
+    /// Gets an optional str literal
+    pub(crate) fn str(&self, name: &str) -> syn::Result<Option<syn::LitStr>> {
+        match self.get(name) {
+            Some(arg) => match arg.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(ref s),
+                    ..
+                }) => Ok(Some(s.clone())),
+                _ => Err(self.expected_error(
+                    arg.value.span(),
+                    format!("The field '{}' needs to be a str literal!", name),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets all str literals of a field which can be repeated
+    pub(crate) fn all_str(&self, name: &str) -> syn::Result<Vec<syn::LitStr>> {
+        self.args
             .iter()
-            .filter_map(|x| {
-                if let syn::NestedMeta::Meta(ref meta) = x {
-                    if meta.path().is_ident(&arg) {
-                        return Some(meta);
-                    }
-                }
-                None
+            .filter(|arg| arg.name == name)
+            .map(|arg| match arg.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(ref s),
+                    ..
+                }) => Ok(s.clone()),
+                _ => Err(self.expected_error(
+                    arg.value.span(),
+                    format!("The field '{}' needs to be a str literal!", name),
+                )),
             })
-            .next();
-        if let Some(meta) = meta {
-            if let syn::Meta::NameValue(ref meta) = meta {
-                let meta_lit = meta.lit.clone();
-                return match meta_lit {
-                    syn::Lit::Str(s) => Ok(s),
-                    _ => {
-                        let error = syn::Error::new(
-                            meta.lit.span(),
-                            format!(
-                                "expected `{}`\n\nThe field '{}' needs to be a str literal!",
-                                expected, arg
-                            ),
-                        )
-                        .to_compile_error();
-                        Err(quote! {#error}.into())
-                    }
-                };
-            }
-        } else {
-            let error = syn::Error::new(
-                meta.span(),
+            .collect()
+    }
+
+    /// Gets an optional bool literal
+    pub(crate) fn bool(&self, name: &str) -> syn::Result<Option<bool>> {
+        match self.get(name) {
+            Some(arg) => match arg.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Bool(ref b),
+                    ..
+                }) => Ok(Some(b.value)),
+                _ => Err(self.expected_error(
+                    arg.value.span(),
+                    format!("The field '{}' needs to be a bool literal!", name),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets an optional integer literal
+    pub(crate) fn int(&self, name: &str) -> syn::Result<Option<i64>> {
+        match self.get(name) {
+            Some(arg) => match arg.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Int(ref i),
+                    ..
+                }) => i.base10_parse().map(Some),
+                _ => Err(self.expected_error(
+                    arg.value.span(),
+                    format!("The field '{}' needs to be an integer literal!", name),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets an optional duration like `"30s"` in milliseconds
+    pub(crate) fn duration_millis(&self, name: &str) -> syn::Result<Option<u64>> {
+        match self.str(name)? {
+            Some(lit) => parse_duration_millis(&lit.value())
+                .map(Some)
+                .ok_or_else(|| {
+                    self.expected_error(
+                        lit.span(),
+                        format!(
+                            "The field '{}' needs to be a duration like \"30s\", \"5m\" or \"1h30m\"!",
+                            name
+                        ),
+                    )
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets an optional path like `crate::commands::fallback`
+    pub(crate) fn path(&self, name: &str) -> syn::Result<Option<syn::Path>> {
+        match self.get(name) {
+            Some(arg) => match arg.value {
+                syn::Expr::Path(ref path) => Ok(Some(path.path.clone())),
+                _ => Err(self.expected_error(
+                    arg.value.span(),
+                    format!("The field '{}' needs to be a path!", name),
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a required type like `MyState` or `Config<'static>`
+    pub(crate) fn required_type(&self, name: &str) -> syn::Result<syn::Type> {
+        let arg = self.get(name).ok_or_else(|| {
+            self.expected_error(self.span, format!("The field '{}' is required!", name))
+        })?;
+        match arg.value {
+            syn::Expr::Path(ref path) => Ok(syn::Type::Path(syn::TypePath {
+                qself: path.qself.clone(),
+                path: path.path.clone(),
+            })),
+            syn::Expr::Verbatim(ref tokens) => syn::parse2(tokens.clone()),
+            _ => Err(self.expected_error(
+                arg.value.span(),
+                format!("The field '{}' needs to be a type!", name),
+            )),
+        }
+    }
+
+    /// Gets either a single str literal or an array of them
+    pub(crate) fn str_list(&self, name: &str) -> syn::Result<Option<Vec<syn::LitStr>>> {
+        let arg = match self.get(name) {
+            Some(arg) => arg,
+            None => return Ok(None),
+        };
+        let error = || {
+            self.expected_error(
+                arg.value.span(),
                 format!(
-                    "1expected `{}`\n\nThe field '{}' is required!",
-                    expected, arg
+                    "The field '{}' needs to be a str literal or an array of them!",
+                    name
                 ),
             )
-            .to_compile_error();
-            return Err(quote! {#error}.into());
-        }
-    }
-    let error = syn::Error::new(
-        input_span,
-        format!(
-            "expected `{}` but not enough arguments where provided.",
-            expected
-        ),
-    )
-    .to_compile_error();
-    Err(quote! {#error}.into())
+        };
+        match arg.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(ref s),
+                ..
+            }) => Ok(Some(vec![s.clone()])),
+            syn::Expr::Array(ref array) => array
+                .elems
+                .iter()
+                .map(|elem| match elem {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(s),
+                        ..
+                    }) => Ok(s.clone()),
+                    _ => Err(error()),
+                })
+                .collect::<syn::Result<Vec<_>>>()
+                .map(Some),
+            _ => Err(error()),
+        }
+    }
+}
+
+/// Parses a duration like `mrsbfh::utils::parse_duration` does so it can be checked at compile time
+pub(crate) fn parse_duration_millis(input: &str) -> Option<u64> {
+    let mut rest = input.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut millis = 0u64;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let value: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60 * 1_000,
+            "h" => 60 * 60 * 1_000,
+            "d" => 24 * 60 * 60 * 1_000,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+        millis = millis.checked_add(value.checked_mul(unit)?)?;
+    }
+    Some(millis)
 }
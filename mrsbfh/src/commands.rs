@@ -6,8 +6,8 @@
 //!
 //! These functions require a specific syntax which is described below.
 //!
-//! Also that function requires you to have a config struct which implements the [Loader](crate::config::Loader)
-//! trait.
+//! All commands share the state of the bot, which can be any type implementing [State]. This is
+//! usually your config struct deriving [ConfigDerive](crate::config::ConfigDerive).
 //!
 //! <br>
 //!
@@ -17,35 +17,297 @@
 //! In each of these submodules you can define a command like this:
 //!
 //! ```compile_fail
-//! use crate::config::Config;
 //! use crate::errors::Error;
 //! use matrix_sdk::ruma::events::{room::message::MessageEventContent, AnyMessageEventContent};
-//! use matrix_sdk::ruma::RoomId;
-//! use matrix_sdk::Client;
-//! use mrsbfh::commands::command;
-//! use std::sync::Arc;
-//! use tokio::sync::Mutex;
+//! use mrsbfh::commands::{command, Responder};
 //!
 //! #[command(help = "`!hello_world` - Prints \"hello world\".")]
-//! pub async fn hello_world<'a>(
-//!     _client: Client,
-//!     tx: mrsbfh::Sender,
-//!     _config: Arc<Mutex<Config<'a>>>,
-//!     _sender: String,
-//!     _room_id: RoomId,
-//!     mut _args: Vec<&str>,
-//! ) -> Result<(), Error>
-//! where
-//!     Config<'a>: mrsbfh::config::Loader + Clone,
-//! {
+//! pub async fn hello_world(responder: Responder) -> Result<(), Error> {
 //!     let content =
 //!         AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain("Hello World!"));
 //!
-//!     tx.send(content).await?;
+//!     responder.send(content).await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Parameters
+//!
+//! Everything about the invocation is in the [CommandContext]. A command can take it as a whole or
+//! only the pieces it needs, in any order. These parameters are recognized by their type:
+//!
+//! * [CommandContext] itself
+//! * `Client` and the `Joined` room the command was used in
+//! * `Arc<MyState>` for the [State] of the bot
+//! * [Responder] to send answers
+//! * [SenderId], [TriggerEventId], [Body], [FormattedBody] and [ThreadRoot]
+//! * [CancellationToken] to find out if the command got cancelled
+//!
+//! All other parameters are arguments of the command, see [Typed arguments](#typed-arguments).
+//!
+//! ```compile_fail
+//! #[command(help = "`!whoami` - Tells you who you are.")]
+//! pub async fn whoami(SenderId(sender): SenderId, room: Joined, responder: Responder) -> Result<(), Error> {
+//!     responder.send_notice(format!("You are {} in {}", sender, room.room_id())).await?;
 //!     Ok(())
 //! }
 //! ```
 //!
+//! The old signature `(client, tx: mrsbfh::Sender, config, sender: String, room_id: RoomId, args)`
+//! is still supported.
+//!
+//! <br>
+//!
+//! ### Replies
+//!
+//! The [Responder] can send messages as rich replies to the message containing the command using
+//! [reply_notice](Responder::reply_notice) and friends. To turn every message of a command into a
+//! reply, including the usage and error messages, use `reply = true`:
+//!
+//! ```compile_fail
+//! #[command(help = "`!ping` - Answers with pong.", reply = true)]
+//! pub async fn ping(responder: Responder) -> Result<(), Error> {
+//!     responder.send_notice("pong").await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Editing answers
+//!
+//! Every method of the [Responder] returns a [SentMessage] which can be used to edit the message
+//! later. This is useful to show the progress of long running commands without flooding the room:
+//!
+//! ```compile_fail
+//! #[command(help = "`!import` - Imports everything.")]
+//! pub async fn import(responder: Responder) -> Result<(), Error> {
+//!     let mut progress = responder.send_notice("Working… 0%").await?;
+//!     for percent in (10..=100).step_by(10) {
+//!         do_some_work().await;
+//!         progress.edit(MessageEventContent::notice_plain(format!("Working… {}%", percent))).await?;
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The messages get sent in the background. To find out if sending worked, wait for
+//! [delivered](SentMessage::delivered) or use [deliver](Responder::deliver). Both resolve to the
+//! event id of the message or the [DeliveryError](crate::errors::DeliveryError) which prevented
+//! sending it:
+//!
+//! ```compile_fail
+//! let content = AnyMessageEventContent::RoomMessage(MessageEventContent::notice_plain("Done"));
+//! match responder.deliver(content).await {
+//!     Ok(event_id) => remember_for_cleanup(event_id),
+//!     Err(e) => tracing::warn!("Couldn't send the result: {}", e),
+//! }
+//! ```
+//!
+//! Commands whose message gets edited, like fixing a typo in `!deploy stagin`, run again if
+//! `rerun_edits` is set in the [CommandsConfig](crate::config::CommandsConfig). The answers of the
+//! new run edit the ones of the earlier run in the order they were sent. Only further answers get
+//! sent as new messages. [CommandContext::is_edit] tells a command that it runs again. Edits by
+//! anyone but the sender of the message are ignored.
+//!
+//! <br>
+//!
+//! ### Other actions
+//!
+//! Besides answering, the [Responder] can send messages to other rooms, set state events, react,
+//! redact, invite and show the typing notice. Each of these is an [Action] which gets performed
+//! by the code generated by the `#[commands]` macro, so commands don't need the `Client` for them.
+//!
+//! ```compile_fail
+//! #[command(help = "`!announce <text>` - Posts the text in the announcements room.")]
+//! pub async fn announce(responder: Responder, TriggerEventId(event_id): TriggerEventId, text: String) -> Result<(), Error> {
+//!     responder.typing(true).await?;
+//!     let room_id = RoomId::try_from("!announcements:example.com")?;
+//!     responder.send_to(room_id, AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(text))).await?;
+//!     responder.react(event_id, "📣").await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Threads
+//!
+//! If a command is used in a thread all of its messages get posted into the same thread. Its root
+//! is available as [ThreadRoot] and in the [CommandContext]. Long answers can be moved into a new
+//! thread on the message containing the command by setting `thread_threshold` in the
+//! [CommandsConfig](crate::config::CommandsConfig) to the number of lines an answer may have in
+//! the main timeline. A command can also always start a thread:
+//!
+//! ```compile_fail
+//! #[command(help = "`!report` - Shows a long report.")]
+//! pub async fn report(mut responder: Responder, TriggerEventId(event_id): TriggerEventId) -> Result<(), Error> {
+//!     if responder.thread_root().is_none() {
+//!         responder.set_thread_root(event_id);
+//!     }
+//!     responder.send_markdown_notice(&long_report()).await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Aliases
+//!
+//! Every command can also be invoked using a short form made of the first letter of each word
+//! (`!hw` for `!hello_world`). Further names can be added with `aliases`, and the short form can be
+//! turned off with `short = false`:
+//!
+//! ```compile_fail
+//! #[command(help = "`!hello_world` - Prints \"hello world\".", aliases = ["hi", "hey"], short = false)]
+//! pub async fn hello_world(
+//!     // ...
+//! ) -> Result<(), Error> {
+//!     // ...
+//! }
+//! ```
+//!
+//! The `command_generate` macro fails to compile if two commands end up with the same name, alias
+//! or short form, or if one of them collides with the built-in `help` and `h`.
+//!
+//! <br>
+//!
+//! ### Detailed help
+//!
+//! The overview of the bot only lists the first line of `help`. `!help <command>` shows a detailed
+//! page which can be filled with a `usage`, a `long_help` and any number of `example`s. Both
+//! `usage` and the examples are written without the prefix since it gets added automatically.
+//! Commands with typed arguments get their `usage` generated if it is not set.
+//!
+//! ```compile_fail
+//! #[command(
+//!     help = "`!remind` - Reminds you about something.",
+//!     long_help = "The reminder gets sent to the room the command was used in.",
+//!     example = "remind 10 tea",
+//!     example = "remind 60 \"team standup\""
+//! )]
+//! pub async fn remind(
+//!     // ...
+//! ) -> Result<(), Error> {
+//!     // ...
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Permissions
+//!
+//! Commands can be restricted to users with a minimum power level in the room and/or to users with
+//! a role. Roles are mapped to MXIDs in the [CommandsConfig](crate::config::CommandsConfig) of your
+//! config. If several roles are given any of them is enough, but all other requirements have to be
+//! met as well.
+//!
+//! ```compile_fail
+//! #[command(help = "`!kick <user>` - Kicks a user.", min_power_level = 50, role = "admin")]
+//! pub async fn kick(
+//!     // ...
+//! ) -> Result<(), Error> {
+//!     // ...
+//! }
+//! ```
+//!
+//! Denied invocations never reach the command. The bot replies with the `refusal_message` of the
+//! config (or a default one) and logs a warning.
+//!
+//! <br>
+//!
+//! ### Cooldowns
+//!
+//! `cooldown = "30s"` makes a command unusable for that time after it was used. By default every
+//! user has their own cooldown, `per = "room"` shares it between all users of a room and
+//! `per = "global"` between everyone.
+//!
+//! ```compile_fail
+//! #[command(help = "`!expensive_report` - Takes a while.", cooldown = "5m", per = "room")]
+//! pub async fn expensive_report(
+//!     // ...
+//! ) -> Result<(), Error> {
+//!     // ...
+//! }
+//! ```
+//!
+//! Users get told how long they have to wait instead. The `cooldowns` of the
+//! [CommandsConfig](crate::config::CommandsConfig) replace the ones of the commands by their primary
+//! name, the name of the function (`hello_world`), which is also used in the replies.
+//! Limiting how many commands each room can use in total is done by the `room_budget` of
+//! `command_generate`.
+//!
+//! <br>
+//!
+//! ### Timeouts
+//!
+//! `timeout = "60s"` limits how long a command may run. Afterwards the bot stops waiting for it
+//! and tells the user that it timed out. Commands without a `timeout` use the one set using
+//! `#[command_generate(timeout = "5m")]` or the `default_timeout` of the
+//! [CommandsConfig](crate::config::CommandsConfig).
+//!
+//! Redacting the message containing a command cancels it as well, if [cancel_redacted] is
+//! registered as an event handler. Commands which spawn their own work can take the
+//! [CancellationToken] to find out when to stop:
+//!
+//! ```compile_fail
+//! #[command(help = "`!build` - Builds everything.", timeout = "10m")]
+//! pub async fn build(responder: Responder, cancellation: CancellationToken) -> Result<(), Error> {
+//!     for step in build_steps() {
+//!         if cancellation.is_cancelled() {
+//!             return Ok(());
+//!         }
+//!         step.run().await;
+//!     }
+//!     responder.send_notice("Done").await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! <br>
+//!
+//! ### Running several commands
+//!
+//! By default every command runs as soon as its message arrives. The `dispatch` settings of the
+//! [CommandsConfig](crate::config::CommandsConfig) limit how many commands run at the same time
+//! and can run the commands of each room (or user) one after another, so their answers don't get
+//! mixed up. The order is the one in which the messages arrived at the bot, which for messages of
+//! the same sync isn't guaranteed to match the timeline. Commands which have to wait while
+//! `queue_depth` others are waiting already get rejected.
+//! Messages which don't contain a known command never take a place. See [Dispatcher] for the
+//! details.
+//!
+//! <br>
+//!
+//! ### Typed arguments
+//!
+//! Instead of taking the raw `args: Vec<&str>` a command can declare typed parameters. These get
+//! parsed using the [FromArg] trait before the command is called.
+//!
+//! * Plain parameters like `count: u32` are required.
+//! * `Option<T>` parameters are optional and can only be followed by other optional ones.
+//! * A trailing `Vec<T>` collects all remaining arguments.
+//! * A trailing `String` gets the rest of the line as it was written. Only if the whole rest is
+//!   quoted like `"team standup"` the quotes get removed.
+//!
+//! ```compile_fail
+//! #[command(help = "`!remind <minutes> <what>` - Reminds you about something.")]
+//! pub async fn remind(responder: Responder, minutes: u32, what: String) -> Result<(), Error> {
+//!     // ...
+//! }
+//! ```
+//!
+//! The message is split into arguments using [tokenize](command_utils::tokenize), so users can
+//! pass arguments containing spaces by quoting them like `!remind 10 "team standup"`. This only
+//! happens once the command turned out to be one of the bot, so quotes in messages for other bots
+//! are never complained about.
+//!
+//! If the arguments don't match the signature the bot replies with the error and a usage text like
+//! `!remind <minutes> <what...>` which is generated from the signature. The command itself is not
+//! called in that case.
+//!
 //! <br>
 //!
 //! ## `#[command_generate]` macro
@@ -63,22 +325,106 @@
 //!
 //! #[command_generate(
 //!     bot_name = "Example",
-//!     description = "This bot prints hello!"
+//!     description = "This bot prints hello!",
+//!     state = Config<'static>,
+//!     error = Error
 //! )]
 //! enum Commands {
 //!     Hello_World
 //! }
 //! ```
 //!
-//! This does generate a `match_command` function which takes the following arguments:
-//!
-//! `(client: Client, tx: mrsbfh::Sender, config: Arc<Mutex<Config<'a>>>, sender: String, room_id: RoomId, args: Vec<&str>)`
-//!
-//! and it returns: `Result<(), Error>` where Error is an Error struct you provide.
+//! This does generate a `match_command` function which takes a `CommandContext` of the `state`
+//! created using [CommandContext::from_event] and returns a `Result` with the `error`.
 //!
 //! This can either be called by you or you can continue reading and instead use another macro to
 //! do this for you.
 //!
+//! The state is shared by all commands as an `Arc`. It can be the config itself, the config in a
+//! tokio `Mutex` or `RwLock` to change it at runtime or your own type which holds whatever else
+//! the commands need, like database pools. See [State] for implementing it.
+//!
+//! The error type has to implement [CommandError](crate::errors::CommandError). Errors returned by
+//! commands get logged with a correlation id and the user gets told about them as decided by it.
+//! An empty `impl CommandError for Error {}` sends a short notice containing the id. Commands
+//! which panic get reported the same way as a [CommandPanic](crate::errors::CommandPanic).
+//!
+//! The `body` of the context is the full message. It only gets handled if it starts with one of the prefixes of
+//! the bot. By default this is `!` but you can change it with `prefix = "?"` or use multiple ones
+//! like `prefix = ["!", "?bot "]`. If your config has a [CommandsConfig](crate::config::CommandsConfig)
+//! its `prefixes` replace these at runtime.
+//!
+//! Setting `mention = true` additionally allows to invoke commands by mentioning the bot at the
+//! start of the message. This works with the display name (`ExampleBot: hello_world`), the bare
+//! MXID or a mention pill in the `formatted_body`.
+//!
+//! `room_budget = "20/1m"` limits every room to 20 commands per minute. Only commands which exist
+//! count, those exceeding this get answered with the time until the next one is possible.
+//!
+//! With `reactions` set in the [CommandsConfig](crate::config::CommandsConfig) the bot reacts to
+//! recognized commands while they run and with their result afterwards, see [StatusReactions].
+//! Commands which are denied, get bad arguments, time out or get cancelled count as failed.
+//!
+//! ### Unknown commands
+//!
+//! Commands which don't exist are ignored by default. `suggestions = true` makes the bot reply with
+//! the closest commands like "Did you mean `!hello_world`?" instead. To handle them yourself pass a
+//! function to `fallback`. It gets the names of the commands in the group the unknown one was used
+//! in and can use [send_suggestions] with them. This covers unknown subcommands of groups as well.
+//!
+//! ```compile_fail
+//! #[command_generate(
+//!     bot_name = "Example",
+//!     description = "This bot prints hello!",
+//!     state = Config<'static>,
+//!     error = Error,
+//!     fallback = unknown
+//! )]
+//! enum Commands {
+//!     Hello_World
+//! }
+//!
+//! async fn unknown(
+//!     cmd: &str,
+//!     names: &[&[&str]],
+//!     ctx: CommandContext<Config<'static>>,
+//! ) -> Result<(), Error> {
+//!     mrsbfh::commands::send_suggestions(&ctx.responder, cmd, names).await;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ### Command groups
+//!
+//! Commands like `!room list` and `!room join` can be put into a group. The group is a submodule
+//! which is marked with `#[group]` in the enum and uses the `command_group` macro instead.
+//!
+//! ```compile_fail
+//! // commands/mod.rs
+//! pub mod room;
+//!
+//! #[command_generate(bot_name = "Admin", description = "Manages things", state = MyState, error = Error)]
+//! enum Commands {
+//!     #[group]
+//!     Room,
+//! }
+//!
+//! // commands/room/mod.rs
+//! use mrsbfh::commands::command_group;
+//!
+//! pub mod join;
+//! pub mod list;
+//!
+//! #[command_group(description = "Manage the rooms of the bot", state = MyState)]
+//! enum Commands {
+//!     Join,
+//!     List,
+//! }
+//! ```
+//!
+//! Groups can be nested. The help lists each group in its own section and `!help room` only shows
+//! the subcommands of that group. `!help room join` shows the detailed help of a subcommand.
+//!
 //! <br>
 //!
 //! ## `#[commands]` macro
@@ -107,7 +453,8 @@
 //!     event: SyncMessageEvent<MessageEventContent>,
 //!     room: Room,
 //!     client: Client,
-//!     config: Arc<Mutex<Config<'static>>>,
+//!     raw: RawEvent,
+//!     config: Arc<Config<'static>>,
 //! ) {
 //!     println!("message example")
 //! }
@@ -115,29 +462,770 @@
 //!
 //! You use it using this snippet:
 //!
-//! ```
+//! ```compile_fail
 //! client
-//!     .register_event_handler(move |ev, room, client| {
-//!         sync::on_room_message(ev, room, client, config.clone())
+//!     .register_event_handler(move |ev, room, client, raw| {
+//!         sync::on_room_message(ev, room, client, raw, config.clone())
 //!     })
 //!     .await;
 //! ```
 //!
+//! The state parameter is named `state` or `config` and holds the `state` of `command_generate`
+//! in an `Arc`.
+//!
+//! The `raw: RawEvent` parameter is optional. It is used to find the thread of the message. Without
+//! it a command gets fetched again from the server if its message has a relation.
+//!
+//! Messages of the bot itself and notices are never handled as commands, so the bot can't trigger
+//! itself or get into a loop with other bots. This and a list of ignored users can be changed
+//! using the `filter` of the [CommandsConfig](crate::config::CommandsConfig), see
+//! [MessageFilter](crate::sync::MessageFilter).
+//!
+//! The initial sync after a restart delivers messages which were already handled. Messages sent
+//! before the [startup_time](crate::sync::startup_time) of the bot are ignored, except for the
+//! ones within the `startup_grace` of the [CommandsConfig](crate::config::CommandsConfig). Setting
+//! its `processed_events_path` additionally remembers the handled commands in a file, so no
+//! command runs twice.
+//!
 //! <br>
 //!
 //! **This does have some requirements:**
 //! * Your `match_command` function MUST be imported
 //!
 
+mod args;
+mod cancellation;
+mod context;
+mod dispatcher;
+mod limits;
+mod permissions;
+mod reactions;
+mod responder;
+
+pub use args::{send_usage, ArgParser, FromArg};
+pub use cancellation::{cancel_redacted, run_cancellable, CancellationToken, RunningCommand};
+pub use context::{
+    Body, CommandContext, FormattedBody, FromContext, SenderId, State, ThreadRoot, TriggerEventId,
+};
+pub use dispatcher::{
+    dispatch, send_answers, DispatchPermit, DispatchSlot, Dispatcher, Reservation,
+};
+pub use limits::{check_cooldown, check_room_budget, CommandCooldown};
+pub use permissions::{check_permissions, Permissions};
+pub use reactions::StatusReactions;
+pub use responder::{Action, Outgoing, Responder, SentMessage};
+
+/// Renders the markdown to html and sends both as a notice, logging any errors
+pub async fn send_markdown_notice(responder: &Responder, markdown: &str) {
+    if let Err(e) = responder.send_markdown_notice(markdown).await {
+        tracing::error!("Error: {}", e);
+    }
+}
+
+/// Sends the generated help text
+#[doc(hidden)]
+pub async fn send_help(responder: &Responder, help_markdown: &str) {
+    send_markdown_notice(responder, help_markdown).await;
+}
+
+/// The detailed help page of a single command
+///
+/// The `#[command]` macro generates one of these for each command which is shown by
+/// `!help <command>`.
+///
+/// ```
+/// use mrsbfh::commands::CommandHelp;
+///
+/// let help = CommandHelp {
+///     name: "remind",
+///     summary: "`!remind <minutes> <what...>` - Reminds you about something.",
+///     usage: Some("remind <minutes> <what...>"),
+///     long_help: None,
+///     examples: &["remind 10 \"team standup\""],
+///     aliases: &["r"],
+/// };
+/// assert!(help.to_markdown("!").contains("`!remind 10 \"team standup\"`"));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandHelp {
+    pub name: &'static str,
+    /// The one-line summary which is also shown in the overview
+    pub summary: &'static str,
+    /// The usage without the prefix
+    pub usage: Option<&'static str>,
+    pub long_help: Option<&'static str>,
+    /// Example invocations without the prefix
+    pub examples: &'static [&'static str],
+    /// The other names the command can be invoked with
+    pub aliases: &'static [&'static str],
+}
+
+impl CommandHelp {
+    /// Renders the help page using the prefix the command was invoked with
+    pub fn to_markdown(&self, prefix: &str) -> String {
+        let mut markdown = format!("### {}{}\n\n{}\n", prefix, self.name, self.summary);
+        if let Some(usage) = self.usage {
+            markdown.push_str(&format!("\n**Usage:** `{}{}`\n", prefix, usage));
+        }
+        if let Some(long_help) = self.long_help {
+            markdown.push_str(&format!("\n{}\n", long_help));
+        }
+        if !self.aliases.is_empty() {
+            let aliases: Vec<String> = self
+                .aliases
+                .iter()
+                .map(|alias| format!("`{}{}`", prefix, alias))
+                .collect();
+            markdown.push_str(&format!("\n**Aliases:** {}\n", aliases.join(", ")));
+        }
+        if !self.examples.is_empty() {
+            markdown.push_str("\n**Examples:**\n\n");
+            for example in self.examples {
+                markdown.push_str(&format!("* `{}{}`\n", prefix, example));
+            }
+        }
+        markdown
+    }
+}
+
+/// A command which doesn't exist in the group it was used in
+#[doc(hidden)]
+pub struct UnknownCommand<S> {
+    pub name: String,
+    /// The names of the commands in the group
+    pub names: &'static [&'static [&'static str]],
+    /// The prefix of the group like `!room `
+    pub prefix: String,
+    pub ctx: CommandContext<S>,
+}
+
+impl<S> UnknownCommand<S> {
+    pub fn new(
+        name: &str,
+        names: &'static [&'static [&'static str]],
+        ctx: CommandContext<S>,
+    ) -> Self {
+        UnknownCommand {
+            name: name.to_string(),
+            names,
+            prefix: command_utils::current_prefix(),
+            ctx,
+        }
+    }
+}
+
+/// Tells the user that the command doesn't exist and suggests the closest ones
+///
+/// This is what `suggestions = true` does. Custom fallbacks can call it with the names they get.
+pub async fn send_suggestions(responder: &Responder, name: &str, names: &[&[&str]]) {
+    let prefix = command_utils::current_prefix();
+    let closest: Vec<String> = command_utils::closest_names(name, names)
+        .iter()
+        .map(|name| format!("`{}{}`", prefix, name))
+        .collect();
+    let markdown = match closest.split_last() {
+        None => format!(
+            "There is no command called `{}{}`. Use `{}help` to list all commands.",
+            prefix, name, prefix
+        ),
+        Some((last, [])) => format!(
+            "There is no command called `{}{}`. Did you mean {}?",
+            prefix, name, last
+        ),
+        Some((last, rest)) => format!(
+            "There is no command called `{}{}`. Did you mean {} or {}?",
+            prefix,
+            name,
+            rest.join(", "),
+            last
+        ),
+    };
+    send_markdown_notice(responder, &markdown).await;
+}
+
+/// Tells the user that there is no help for the requested command
+#[doc(hidden)]
+pub async fn send_help_not_found(responder: &Responder, path: &[String]) {
+    let prefix = command_utils::current_prefix();
+    let markdown = format!(
+        "There is no command called `{}{}`. Use `{}help` to list all commands.",
+        prefix,
+        path.join(" "),
+        prefix
+    );
+    send_markdown_notice(responder, &markdown).await;
+}
+
+/// Logs the error of a command and tells the user about it
+///
+/// What the user gets to see is decided by the [CommandError](crate::errors::CommandError)
+/// implementation of the error.
+#[doc(hidden)]
+pub async fn report_error<E: crate::errors::CommandError>(
+    responder: &Responder,
+    command: &str,
+    error: &E,
+) {
+    let correlation_id = command_utils::correlation_id();
+    tracing::error!(
+        "Command {} failed (correlation id {}): {}",
+        command,
+        correlation_id,
+        error
+    );
+    if let Some(markdown) = error.user_message(&correlation_id) {
+        send_markdown_notice(responder, &markdown).await;
+    }
+}
+
+/// Runs the future and catches it if it panics
+///
+/// A panicking command would otherwise end the handling of its message without telling anyone.
+///
+/// ```
+/// use mrsbfh::commands::catch_panic;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// assert_eq!(catch_panic(async { 42 }).await.unwrap(), 42);
+///
+/// let panic = catch_panic(async { panic!("oh no") }).await.unwrap_err();
+/// assert_eq!(panic.0, "oh no");
+/// # });
+/// ```
+#[doc(hidden)]
+pub async fn catch_panic<F: std::future::Future>(
+    future: F,
+) -> Result<F::Output, crate::errors::CommandPanic> {
+    let mut future = Box::pin(future);
+    std::future::poll_fn(move |cx| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(std::task::Poll::Ready(output)) => std::task::Poll::Ready(Ok(output)),
+            Ok(std::task::Poll::Pending) => std::task::Poll::Pending,
+            Err(payload) => {
+                std::task::Poll::Ready(Err(crate::errors::CommandPanic(panic_message(&*payload))))
+            }
+        }
+    })
+    .await
+}
+
+/// Runs a known command and reports how it went
+///
+/// The permissions, the room budget and the cooldown get checked before the command waits for its
+/// turn, so refused commands never take a place in the [Dispatcher]. The status reactions start
+/// before the checks and end with `failed` if one of them fails, the dispatcher rejects the
+/// command, `run` returns `None` as the arguments were rejected, the command times out, gets
+/// cancelled, fails or panics.
+#[doc(hidden)]
+pub async fn run_command<S, E, F, Fut>(
+    ctx: CommandContext<S>,
+    command: &str,
+    permissions: &Permissions,
+    cooldown: &CommandCooldown,
+    timeout: Option<std::time::Duration>,
+    run: F,
+) where
+    S: State,
+    E: crate::errors::CommandError,
+    F: FnOnce(CommandContext<S>) -> Fut,
+    Fut: std::future::Future<Output = Option<Result<(), E>>>,
+{
+    let responder = ctx.responder.clone();
+    let reactions = StatusReactions::received(&ctx).await;
+    let success = async {
+        if !check_permissions(&ctx, command, permissions).await
+            || !check_room_budget(&ctx, ctx.room_budget).await
+            || !check_cooldown(&ctx, command, cooldown).await
+        {
+            // Refused commands never wait for a permit
+            ctx.dispatch_slot.release();
+            return false;
+        }
+        if !dispatch(&ctx, command).await {
+            return false;
+        }
+        let timeout = timeout.or(ctx.default_timeout);
+        let cancellation = ctx.cancellation.clone();
+        let _running = RunningCommand::register(&ctx);
+        let result = catch_panic(run_cancellable(
+            &responder,
+            command,
+            cancellation,
+            timeout,
+            run(ctx),
+        ))
+        .await;
+        match result {
+            Ok(Some(Some(Ok(())))) => true,
+            Ok(Some(Some(Err(ref e)))) => {
+                report_error(&responder, command, e).await;
+                false
+            }
+            Ok(Some(None)) | Ok(None) => false,
+            Err(ref panic) => {
+                report_error(&responder, command, panic).await;
+                false
+            }
+        }
+    }
+    .await;
+    if let Some(reactions) = reactions {
+        reactions.finish(success).await;
+    }
+}
+
+/// The message passed to `panic!` if it was a string
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => String::from("unknown panic"),
+        },
+    }
+}
+
 pub mod command_utils {
+    use crate::errors::TokenizeError;
     use lazy_static::lazy_static;
 
     lazy_static! {
-        pub static ref WHITESPACE_DEDUPLICATOR_MAGIC: regex::Regex =
-            regex::Regex::new(r"\s+").unwrap();
-        pub static ref COMMAND_MATCHER_MAGIC: regex::Regex =
-            regex::Regex::new(r"!([\w-]+)").unwrap();
+        pub static ref COMMAND_NAME_MAGIC: regex::Regex = regex::Regex::new(r"^[\w-]+$").unwrap();
+    }
+
+    tokio::task_local! {
+        /// The prefix which was used to invoke the currently running command
+        pub static PREFIX: String;
+    }
+
+    /// The prefix of the currently running command or `!` if there is none
+    pub fn current_prefix() -> String {
+        PREFIX
+            .try_with(|prefix| prefix.clone())
+            .unwrap_or_else(|_| String::from("!"))
+    }
+
+    /// Compares two strings in a const context
+    #[doc(hidden)]
+    pub const fn names_equal(a: &str, b: &str) -> bool {
+        let a = a.as_bytes();
+        let b = b.as_bytes();
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    /// Checks if any name is in both lists
+    #[doc(hidden)]
+    pub const fn names_collide(a: &[&str], b: &[&str]) -> bool {
+        let mut i = 0;
+        while i < a.len() {
+            let mut j = 0;
+            while j < b.len() {
+                if names_equal(a[i], b[j]) {
+                    return true;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// Checks if a name appears twice in the list
+    #[doc(hidden)]
+    pub const fn has_duplicate_names(names: &[&str]) -> bool {
+        let mut i = 0;
+        while i < names.len() {
+            let mut j = i + 1;
+            while j < names.len() {
+                if names_equal(names[i], names[j]) {
+                    return true;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    /// A command found in a message
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ParsedCommand {
+        /// The prefix the message started with
+        pub prefix: String,
+        /// The lowercase name of the command
+        pub name: String,
+        /// The text after the name as it was written
+        ///
+        /// It only gets split into arguments once the command is known, so messages meant for
+        /// other bots never fail because of their quoting.
+        pub rest: String,
+    }
+
+    /// Checks if the body starts with one of the prefixes and splits it into the command and the
+    /// rest of the message
+    ///
+    /// If multiple prefixes match the longest one wins. Returns `None` if the body isn't a command.
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::parse_command;
+    ///
+    /// let prefixes = ["!", "?bot "];
+    /// let command = parse_command("?bot hello_world don't  panic", &prefixes).unwrap();
+    /// assert_eq!(command.name, "hello_world");
+    /// assert_eq!(command.rest, "don't  panic");
+    ///
+    /// assert!(parse_command("hey !hello_world", &prefixes).is_none());
+    /// ```
+    pub fn parse_command<S: AsRef<str>>(body: &str, prefixes: &[S]) -> Option<ParsedCommand> {
+        let prefix = prefixes
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|prefix| !prefix.is_empty() && body.starts_with(prefix))
+            .max_by_key(|prefix| prefix.len())?;
+
+        let mut rest = &body[prefix.len()..];
+        if prefix.ends_with(char::is_whitespace) {
+            rest = rest.trim_start();
+        }
+        split_command(prefix, rest)
+    }
+
+    fn split_command(prefix: &str, rest: &str) -> Option<ParsedCommand> {
+        if rest.starts_with(char::is_whitespace) || rest.is_empty() {
+            return None;
+        }
+
+        let (name, rest) = split_first_word(rest);
+        let name = name.to_lowercase();
+        if !COMMAND_NAME_MAGIC.is_match(&name) {
+            return None;
+        }
+
+        Some(ParsedCommand {
+            prefix: prefix.to_string(),
+            name,
+            rest: rest.to_string(),
+        })
+    }
+
+    /// Splits the first word off the input and returns it together with the text after it
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::split_first_word;
+    ///
+    /// assert_eq!(split_first_word("  list  all rooms"), ("list", "all rooms"));
+    /// assert_eq!(split_first_word(""), ("", ""));
+    /// ```
+    pub fn split_first_word(input: &str) -> (&str, &str) {
+        let input = input.trim_start();
+        match input.find(char::is_whitespace) {
+            Some(end) => (&input[..end], input[end..].trim_start()),
+            None => (input, ""),
+        }
+    }
+
+    lazy_static! {
+        static ref PILL_MAGIC: regex::Regex = regex::Regex::new(
+            r#"^\s*<a\s[^>]*href=["']https://matrix\.to/#/([^"'?]+)[^"']*["'][^>]*>(.*?)</a>"#
+        )
+        .unwrap();
+    }
+
+    /// Decodes the `%xx` escapes of a matrix.to link
+    fn percent_decode(input: &str) -> String {
+        let bytes = input.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' && i + 2 < bytes.len() {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    /// Strips `name` and an optional `:` or `,` from the start of the body
+    fn strip_name<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+        if name.is_empty() || body.len() < name.len() || !body.is_char_boundary(name.len()) {
+            return None;
+        }
+        let (start, rest) = body.split_at(name.len());
+        if start.to_lowercase() != name.to_lowercase() {
+            return None;
+        }
+        let rest = rest
+            .strip_prefix(':')
+            .or_else(|| rest.strip_prefix(','))
+            .unwrap_or(rest);
+        if !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        Some(rest.trim_start())
+    }
+
+    /// Checks if the message starts with a mention of the bot and strips it
+    ///
+    /// A mention can either be a matrix.to pill in the `formatted_body`, the bare MXID or the
+    /// display name of the bot followed by an optional `:` or `,`. On success it returns the
+    /// mention as it was written by the user together with the remaining body.
+    ///
+    /// ```
+    /// use matrix_sdk::ruma::UserId;
+    /// use mrsbfh::commands::command_utils::strip_mention;
+    /// use std::convert::TryFrom;
+    ///
+    /// let bot = UserId::try_from("@bot:example.com").unwrap();
+    /// let (mention, rest) = strip_mention("ExampleBot: hello_world", None, &bot, Some("ExampleBot")).unwrap();
+    /// assert_eq!(mention, "ExampleBot: ");
+    /// assert_eq!(rest, "hello_world");
+    ///
+    /// let formatted = r#"<a href="https://matrix.to/#/@bot:example.com">Bot</a>: hello_world"#;
+    /// let (_, rest) = strip_mention("Bot: hello_world", Some(formatted), &bot, None).unwrap();
+    /// assert_eq!(rest, "hello_world");
+    /// ```
+    pub fn strip_mention<'a>(
+        body: &'a str,
+        formatted_body: Option<&str>,
+        user_id: &matrix_sdk::ruma::UserId,
+        display_name: Option<&str>,
+    ) -> Option<(String, &'a str)> {
+        let body = body.trim_start();
+
+        let pill_text = formatted_body
+            .and_then(|formatted_body| PILL_MAGIC.captures(formatted_body))
+            .filter(|caps| percent_decode(&caps[1]) == user_id.as_str())
+            .map(|caps| caps[2].to_string());
+
+        let names = pill_text
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::once(user_id.as_str()))
+            .chain(display_name);
+        for name in names {
+            if let Some(rest) = strip_name(body, name) {
+                let mention = body[..body.len() - rest.len()].to_string();
+                return Some((mention, rest));
+            }
+        }
+        None
+    }
+
+    /// Parses a command which follows a mention of the bot
+    ///
+    /// The command can be written with or without one of the prefixes.
+    pub fn parse_mention_command<S: AsRef<str>>(
+        body: &str,
+        formatted_body: Option<&str>,
+        user_id: &matrix_sdk::ruma::UserId,
+        display_name: Option<&str>,
+        prefixes: &[S],
+    ) -> Option<ParsedCommand> {
+        let (mention, rest) = strip_mention(body, formatted_body, user_id, display_name)?;
+        parse_command(rest, prefixes).or_else(|| split_command(&mention, rest))
+    }
+
+    /// Resolves the identity of the bot and parses a command which follows a mention of it
+    ///
+    /// The display name is taken from the member event of the bot in the room.
+    pub async fn resolve_mention_command<S: AsRef<str>>(
+        client: &matrix_sdk::Client,
+        room_id: &matrix_sdk::ruma::RoomId,
+        body: &str,
+        formatted_body: Option<&str>,
+        prefixes: &[S],
+    ) -> Option<ParsedCommand> {
+        let user_id = client.user_id().await?;
+        let display_name = match client.get_joined_room(room_id) {
+            Some(room) => match room.get_member_no_sync(&user_id).await {
+                Ok(Some(member)) => member.display_name().map(String::from),
+                _ => None,
+            },
+            None => None,
+        };
+        parse_mention_command(
+            body,
+            formatted_body,
+            &user_id,
+            display_name.as_deref(),
+            prefixes,
+        )
+    }
+
+    /// Splits a message body into arguments like a shell would do
+    ///
+    /// * Arguments are separated by any amount of whitespace.
+    /// * Double quotes group words into one argument. Inside of them `\"` and `\\` can be escaped.
+    /// * Single quotes group words as well but don't support any escapes.
+    /// * Outside of quotes a backslash escapes the following character.
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::tokenize;
+    ///
+    /// let args = tokenize(r#"!remind "team standup" 10m it\'s\ time"#).unwrap();
+    /// assert_eq!(args, vec!["!remind", "team standup", "10m", "it's time"]);
+    ///
+    /// assert!(tokenize("!remind \"team standup").is_err());
+    /// ```
+    pub fn tokenize(input: &str) -> Result<Vec<String>, TokenizeError> {
+        let mut tokens = Vec::new();
+        let mut rest = input;
+        while let Some(token) = next_token(rest) {
+            let (token, remaining) = token?;
+            tokens.push(token);
+            rest = remaining;
+        }
+        Ok(tokens)
+    }
+
+    /// Splits off the first argument following the rules of [tokenize] and returns it together
+    /// with the text after it as it was written
+    ///
+    /// Returns `None` if there are no arguments left.
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::next_token;
+    ///
+    /// let (first, rest) = next_token(r#" "team standup"  it's time"#).unwrap().unwrap();
+    /// assert_eq!(first, "team standup");
+    /// assert_eq!(rest, "  it's time");
+    ///
+    /// assert!(next_token("   ").is_none());
+    /// ```
+    pub fn next_token(input: &str) -> Option<Result<(String, &str), TokenizeError>> {
+        let input = input.trim_start();
+        if input.is_empty() {
+            return None;
+        }
+        let mut current = String::new();
+        let mut chars = input.char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' | '\'' => {
+                    let mut terminated = false;
+                    while let Some((_, quoted)) = chars.next() {
+                        if quoted == c {
+                            terminated = true;
+                            break;
+                        }
+                        if c == '"' && quoted == '\\' {
+                            match chars.next() {
+                                Some((_, escaped @ ('"' | '\\'))) => current.push(escaped),
+                                Some((_, other)) => {
+                                    current.push('\\');
+                                    current.push(other);
+                                }
+                                None => current.push('\\'),
+                            }
+                        } else {
+                            current.push(quoted);
+                        }
+                    }
+                    if !terminated {
+                        return Some(Err(TokenizeError::UnterminatedQuote(c)));
+                    }
+                }
+                '\\' => current.push(chars.next().map_or('\\', |(_, escaped)| escaped)),
+                c if c.is_whitespace() => return Some(Ok((current, &input[i..]))),
+                c => current.push(c),
+            }
+        }
+
+        Some(Ok((current, "")))
+    }
+
+    /// The number of single character insertions, deletions and substitutions needed to turn `a`
+    /// into `b`
+    pub fn edit_distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut previous: Vec<usize> = (0..=b.len()).collect();
+        let mut current = vec![0; b.len() + 1];
+        for (i, a) in a.chars().enumerate() {
+            current[0] = i + 1;
+            for (j, b) in b.iter().enumerate() {
+                let substitution = previous[j] + usize::from(a != *b);
+                current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+        previous[b.len()]
+    }
+
+    /// Finds up to three names which are close to the unknown `name`, the closest first
+    ///
+    /// `names` holds the names and aliases of each command like the `command_generate` macro
+    /// generates them in `COMMAND_NAMES`. Names which would have to be replaced completely, like
+    /// `h` for `x`, are never suggested.
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::closest_names;
+    ///
+    /// let names: &[&[&str]] = &[&["hello_world", "hw"], &["help", "h"], &["ping"]];
+    /// assert_eq!(closest_names("helo_world", names), vec!["hello_world"]);
+    /// assert!(closest_names("something", names).is_empty());
+    /// assert!(closest_names("x", names).is_empty());
+    /// ```
+    pub fn closest_names<'a>(name: &str, names: &[&[&'a str]]) -> Vec<&'a str> {
+        // Short names would match almost anything with a larger distance
+        let max_distance = (name.chars().count() / 3).max(1);
+        let mut candidates: Vec<(usize, &'a str)> = names
+            .iter()
+            .flat_map(|names| names.iter())
+            .map(|candidate| (edit_distance(name, candidate), *candidate))
+            .filter(|(distance, candidate)| {
+                *distance <= max_distance && *distance < candidate.chars().count()
+            })
+            .collect();
+        candidates.sort_by_key(|(distance, _)| *distance);
+
+        let mut closest: Vec<&'a str> = Vec::new();
+        for (_, candidate) in candidates {
+            if !closest.contains(&candidate) && closest.len() < 3 {
+                closest.push(candidate);
+            }
+        }
+        closest
+    }
+
+    /// A short id to find the log entry of an error
+    ///
+    /// ```
+    /// use mrsbfh::commands::command_utils::correlation_id;
+    ///
+    /// let id = correlation_id();
+    /// assert_eq!(id.len(), 8);
+    /// assert_ne!(id, correlation_id());
+    /// ```
+    pub fn correlation_id() -> String {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hash, Hasher};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::time::SystemTime;
+
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut hasher = RandomState::new().build_hasher();
+        COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        format!("{:08x}", hasher.finish() as u32)
     }
 }
 
-pub use mrsbfh_macros::{command, command_generate, commands};
+pub use mrsbfh_macros::{command, command_generate, command_group, commands};
@@ -0,0 +1,190 @@
+//! Parsing of typed command arguments
+//!
+//! The `#[command]` macro uses the [ArgParser] to turn the raw arguments of a message into the
+//! typed parameters of a command. Any type implementing [FromArg] can be used as a parameter.
+
+use crate::errors::ArgError;
+use matrix_sdk::ruma::{EventId, RoomAliasId, RoomId, UserId};
+
+/// A type that can be parsed from a single command argument
+///
+/// The returned error is a short description of what was expected and is shown to the user
+/// together with the usage of the command.
+///
+/// ```
+/// use mrsbfh::commands::FromArg;
+///
+/// struct Percent(u8);
+///
+/// impl FromArg for Percent {
+///     fn from_arg(arg: &str) -> Result<Self, String> {
+///         match arg.trim_end_matches('%').parse::<u8>() {
+///             Ok(v) if v <= 100 => Ok(Percent(v)),
+///             _ => Err("expected a percentage between 0% and 100%".to_string()),
+///         }
+///     }
+/// }
+///
+/// assert!(Percent::from_arg("42%").is_ok());
+/// assert!(Percent::from_arg("142%").is_err());
+/// ```
+pub trait FromArg: Sized {
+    fn from_arg(arg: &str) -> Result<Self, String>;
+}
+
+impl FromArg for String {
+    fn from_arg(arg: &str) -> Result<Self, String> {
+        Ok(arg.to_string())
+    }
+}
+
+impl FromArg for char {
+    fn from_arg(arg: &str) -> Result<Self, String> {
+        let mut chars = arg.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err("expected a single character".to_string()),
+        }
+    }
+}
+
+impl FromArg for bool {
+    fn from_arg(arg: &str) -> Result<Self, String> {
+        match arg.to_lowercase().as_str() {
+            "true" | "yes" | "y" | "on" | "1" => Ok(true),
+            "false" | "no" | "n" | "off" | "0" => Ok(false),
+            _ => Err("expected `true` or `false`".to_string()),
+        }
+    }
+}
+
+macro_rules! from_arg_via_from_str {
+    ($expected:literal: $($ty:ty),*) => {
+        $(
+            impl FromArg for $ty {
+                fn from_arg(arg: &str) -> Result<Self, String> {
+                    arg.parse().map_err(|_| $expected.to_string())
+                }
+            }
+        )*
+    };
+}
+
+from_arg_via_from_str!("expected a whole number": i8, i16, i32, i64, i128, isize);
+from_arg_via_from_str!("expected a positive whole number": u8, u16, u32, u64, u128, usize);
+from_arg_via_from_str!("expected a number": f32, f64);
+from_arg_via_from_str!("expected a user id like `@user:example.com`": UserId);
+from_arg_via_from_str!("expected a room id like `!room:example.com`": RoomId);
+from_arg_via_from_str!("expected a room alias like `#room:example.com`": RoomAliasId);
+from_arg_via_from_str!("expected an event id like `$event`": EventId);
+
+/// Walks over the arguments of a command and converts them into typed values
+///
+/// The calls are generated by the `#[command]` macro in the order of the function parameters.
+/// Each argument gets split off the text only when it is needed, so a rest of line `String` keeps
+/// the text exactly as it was written.
+///
+/// ```
+/// use mrsbfh::commands::ArgParser;
+///
+/// let mut parser = ArgParser::new("10  don't  forget");
+/// assert_eq!(parser.required::<u32>("minutes").unwrap(), 10);
+/// assert_eq!(parser.rest_string("what").unwrap(), "don't  forget");
+///
+/// let mut parser = ArgParser::new(r#"10 "team standup""#);
+/// assert_eq!(parser.required::<u32>("minutes").unwrap(), 10);
+/// assert_eq!(parser.rest_string("what").unwrap(), "team standup");
+///
+/// let mut parser = ArgParser::new(r#"10 "team" standup"#);
+/// assert_eq!(parser.required::<u32>("minutes").unwrap(), 10);
+/// assert_eq!(parser.rest_string("what").unwrap(), r#""team" standup"#);
+/// ```
+pub struct ArgParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> ArgParser<'a> {
+    /// `args` is the text after the command name
+    pub fn new(args: &'a str) -> Self {
+        ArgParser { rest: args }
+    }
+
+    /// Parses the next argument and fails if there is none
+    pub fn required<T: FromArg>(&mut self, name: &'static str) -> Result<T, ArgError> {
+        match self.next_arg()? {
+            Some(arg) => parse(name, &arg),
+            None => Err(ArgError::Missing(name)),
+        }
+    }
+
+    /// Parses the next argument if there is one
+    pub fn optional<T: FromArg>(&mut self, name: &'static str) -> Result<Option<T>, ArgError> {
+        self.next_arg()?.map(|arg| parse(name, &arg)).transpose()
+    }
+
+    /// Parses all remaining arguments
+    pub fn rest<T: FromArg>(&mut self, name: &'static str) -> Result<Vec<T>, ArgError> {
+        let mut values = Vec::new();
+        while let Some(arg) = self.next_arg()? {
+            values.push(parse(name, &arg)?);
+        }
+        Ok(values)
+    }
+
+    /// Takes the rest of the line as it was written and fails if it is empty
+    ///
+    /// If the whole rest is a single quoted argument the quotes get removed.
+    pub fn rest_string(&mut self, name: &'static str) -> Result<String, ArgError> {
+        let rest = std::mem::take(&mut self.rest).trim();
+        if rest.is_empty() {
+            return Err(ArgError::Missing(name));
+        }
+        if rest.starts_with(['"', '\'']) {
+            if let Some(Ok((quoted, ""))) = super::command_utils::next_token(rest) {
+                return Ok(quoted);
+            }
+        }
+        Ok(rest.to_string())
+    }
+
+    /// Makes sure that all arguments were consumed
+    pub fn finish(self) -> Result<(), ArgError> {
+        let rest = self.rest.trim();
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            Err(ArgError::TooMany(rest.to_string()))
+        }
+    }
+
+    fn next_arg(&mut self) -> Result<Option<String>, ArgError> {
+        match super::command_utils::next_token(self.rest) {
+            Some(token) => {
+                let (arg, rest) = token?;
+                self.rest = rest;
+                Ok(Some(arg))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse<T: FromArg>(name: &'static str, arg: &str) -> Result<T, ArgError> {
+    T::from_arg(arg).map_err(|reason| ArgError::Invalid {
+        name,
+        value: arg.to_string(),
+        reason,
+    })
+}
+
+/// Replies with the error and the usage of a command after its arguments failed to parse
+#[doc(hidden)]
+pub async fn send_usage(responder: &super::Responder, usage: &str, error: &ArgError) {
+    let markdown = format!(
+        "{}\n\nUsage: `{}{}`",
+        error,
+        super::command_utils::current_prefix(),
+        usage
+    );
+    super::send_markdown_notice(responder, &markdown).await;
+}
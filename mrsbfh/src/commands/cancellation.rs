@@ -0,0 +1,166 @@
+//! Timeouts and cancellation of running commands
+//!
+//! Every command gets a [CancellationToken] which is cancelled once the command timed out or the
+//! message containing it was redacted. The code generated by the `#[command_generate]` macro stops
+//! waiting for the command at that point, long running commands can check the token to stop
+//! their work in between as well.
+
+use super::{CommandContext, Responder};
+use lazy_static::lazy_static;
+use matrix_sdk::event_handler::RawEvent;
+use matrix_sdk::ruma::events::room::redaction::RedactionEventContent;
+use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::EventId;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+lazy_static! {
+    /// The tokens of the running commands by the event containing them
+    static ref RUNNING: Mutex<HashMap<EventId, CancellationToken>> = Mutex::new(HashMap::new());
+}
+
+/// Tells a command that it should stop
+///
+/// ```
+/// use mrsbfh::commands::CancellationToken;
+///
+/// let token = CancellationToken::new();
+/// let clone = token.clone();
+/// assert!(!clone.is_cancelled());
+/// token.cancel();
+/// assert!(clone.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+#[derive(Debug, Default)]
+struct TokenInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token and all of its clones
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token gets cancelled
+    pub async fn cancelled(&self) {
+        // Created before checking so a cancel in between isn't missed
+        let notified = self.inner.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Keeps the token of a command findable for [cancel_redacted] while it runs
+#[derive(Debug)]
+pub struct RunningCommand {
+    event_id: EventId,
+}
+
+impl RunningCommand {
+    pub fn register<S>(ctx: &CommandContext<S>) -> Self {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        running.insert(ctx.event_id.clone(), ctx.cancellation.clone());
+        RunningCommand {
+            event_id: ctx.event_id.clone(),
+        }
+    }
+}
+
+impl Drop for RunningCommand {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        running.remove(&self.event_id);
+    }
+}
+
+/// The part of a redaction the sdk drops when handling it like other message events
+#[derive(Deserialize)]
+struct Redaction {
+    redacts: EventId,
+}
+
+/// Cancels the command of a redacted message if it is still running
+///
+/// The sdk can't hand out redactions with the redacted event, so it is read from the raw event.
+/// Register it next to the handler using `#[commands]`:
+/// ```compile_fail
+/// client.register_event_handler(mrsbfh::commands::cancel_redacted).await;
+/// ```
+pub async fn cancel_redacted(_: SyncMessageEvent<RedactionEventContent>, raw: RawEvent) {
+    let redacts = match serde_json::from_str::<Redaction>(raw.0.get()) {
+        Ok(redaction) => redaction.redacts,
+        Err(e) => {
+            tracing::error!("Failed to read the redacted event: {}", e);
+            return;
+        }
+    };
+    let token = {
+        let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        running.get(&redacts).cloned()
+    };
+    if let Some(token) = token {
+        tracing::info!("Cancelling the command of the redacted {}", redacts);
+        token.cancel();
+    }
+}
+
+/// Runs the command until it finishes, times out or gets cancelled
+///
+/// Returns `None` if the command didn't finish and tells the user if it timed out.
+#[doc(hidden)]
+pub async fn run_cancellable<T>(
+    responder: &Responder,
+    command: &str,
+    token: CancellationToken,
+    timeout: Option<Duration>,
+    command_future: impl Future<Output = T>,
+) -> Option<T> {
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = command_future => Some(result),
+        _ = token.cancelled() => {
+            tracing::info!("Command {} was cancelled", command);
+            None
+        }
+        _ = timed_out => {
+            token.cancel();
+            let timeout = timeout.unwrap_or_default();
+            tracing::warn!("Command {} timed out after {:?}", command, timeout);
+            let markdown = format!(
+                "`{}{}` timed out after {}s.",
+                super::command_utils::current_prefix(),
+                command,
+                super::limits::whole_seconds(timeout)
+            );
+            super::send_markdown_notice(responder, &markdown).await;
+            None
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! Everything a command gets to know about its invocation
+//!
+//! The `#[commands]` macro creates a [CommandContext] for each message and `match_command` fills in
+//! the arguments. Commands can either take the whole context or only the pieces they need using the
+//! [FromContext] extractors.
+
+use super::command_utils::tokenize;
+use super::{CancellationToken, DispatchSlot, Responder};
+use crate::config::{CommandsConfig, Loader};
+use crate::errors::TokenizeError;
+use crate::ratelimit::Budget;
+use crate::reply::ReplyTarget;
+use crate::thread;
+use async_trait::async_trait;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::api::client::r0::room::get_room_event;
+use matrix_sdk::ruma::events::room::message::{
+    MessageEventContent, MessageType, NoticeMessageEventContent, Relation, TextMessageEventContent,
+};
+use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::{EventId, UserId};
+use matrix_sdk::Client;
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+
+/// The state of the bot which all commands share
+///
+/// Commands get it as `Arc<S>`, so anything changing at runtime needs its own lock. This allows
+/// keeping things like database pools next to the config. Configs using
+/// [ConfigDerive](crate::config::ConfigDerive) are a state on their own, wrapped in a tokio
+/// [Mutex] or [RwLock] they can be changed at runtime.
+///
+/// ```compile_fail
+/// pub struct MyState {
+///     pub config: Config<'static>,
+///     pub db: sqlx::PgPool,
+/// }
+///
+/// #[mrsbfh::async_trait::async_trait]
+/// impl mrsbfh::commands::State for MyState {
+///     async fn commands_config(&self) -> Option<mrsbfh::config::CommandsConfig> {
+///         Some(self.config.commands.clone())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait State: Send + Sync + 'static {
+    /// The runtime settings for the commands if the state has any
+    async fn commands_config(&self) -> Option<CommandsConfig> {
+        None
+    }
+}
+
+#[async_trait]
+impl<S: Loader + Send + 'static> State for Mutex<S> {
+    async fn commands_config(&self) -> Option<CommandsConfig> {
+        self.lock().await.commands_config().cloned()
+    }
+}
+
+#[async_trait]
+impl<S: Loader + Send + Sync + 'static> State for RwLock<S> {
+    async fn commands_config(&self) -> Option<CommandsConfig> {
+        self.read().await.commands_config().cloned()
+    }
+}
+
+/// The invocation of a command
+///
+/// `S` is the [State] of the bot.
+pub struct CommandContext<S> {
+    pub client: Client,
+    /// The room the command was used in
+    pub room: Joined,
+    /// The user who used the command
+    pub sender: UserId,
+    /// The event containing the command
+    ///
+    /// For edits this is the edited message.
+    pub event_id: EventId,
+    /// If the command comes from an edit of the message
+    pub is_edit: bool,
+    /// The event of the edit if the command comes from one
+    pub edit_id: Option<EventId>,
+    /// The plain text body of the message
+    pub body: String,
+    /// The html body of the message if it has one
+    pub formatted_body: Option<String>,
+    /// The root of the thread the command was used in
+    pub thread_root: Option<EventId>,
+    /// The text after the command name as it was written, set by `match_command`
+    pub raw_args: String,
+    /// The state of the bot shared by all commands
+    pub state: Arc<S>,
+    pub responder: Responder,
+    /// Gets cancelled if the command times out or its message gets redacted
+    pub cancellation: CancellationToken,
+    /// How long commands without their own `timeout` may run, set by `match_command`
+    pub default_timeout: Option<Duration>,
+    /// The command budget of the room set using `#[command_generate(room_budget = "...")]`, set by
+    /// `match_command`
+    pub room_budget: Option<Budget>,
+    /// Holds the permit of the [Dispatcher](super::Dispatcher) while the command runs
+    pub dispatch_slot: DispatchSlot,
+    /// The runtime settings of the [State], fetched once by `match_command`
+    pub commands_config: Option<Arc<CommandsConfig>>,
+    /// If the message has a relation which might be a thread that wasn't looked up yet
+    thread_unknown: bool,
+}
+
+// Deriving would require `S: Clone`
+impl<S> Clone for CommandContext<S> {
+    fn clone(&self) -> Self {
+        CommandContext {
+            client: self.client.clone(),
+            room: self.room.clone(),
+            sender: self.sender.clone(),
+            event_id: self.event_id.clone(),
+            is_edit: self.is_edit,
+            edit_id: self.edit_id.clone(),
+            body: self.body.clone(),
+            formatted_body: self.formatted_body.clone(),
+            thread_root: self.thread_root.clone(),
+            raw_args: self.raw_args.clone(),
+            state: self.state.clone(),
+            responder: self.responder.clone(),
+            cancellation: self.cancellation.clone(),
+            default_timeout: self.default_timeout,
+            room_budget: self.room_budget,
+            dispatch_slot: self.dispatch_slot.clone(),
+            commands_config: self.commands_config.clone(),
+            thread_unknown: self.thread_unknown,
+        }
+    }
+}
+
+impl<S> CommandContext<S> {
+    /// Creates the context for a text message
+    ///
+    /// Notices are handled like text messages.
+    ///
+    /// Returns `None` for any other kind of message or if the body is empty. For edits the new
+    /// content of the edited message is used. The arguments are filled in by `match_command`. The
+    /// responder replies to the message. The thread isn't part of the event, it gets read using
+    /// [read_thread_root](CommandContext::read_thread_root) or fetched by `match_command` once
+    /// the message turned out to contain a command.
+    pub fn from_event(
+        client: Client,
+        room: Joined,
+        event: &SyncMessageEvent<MessageEventContent>,
+        state: Arc<S>,
+        mut responder: Responder,
+    ) -> Option<Self> {
+        let (content, event_id, is_edit) = match event.content.relates_to {
+            Some(Relation::Replacement(ref replacement)) => {
+                (&*replacement.new_content, &replacement.event_id, true)
+            }
+            _ => (&event.content, &event.event_id, false),
+        };
+        let (body, formatted) = match content.msgtype {
+            MessageType::Text(TextMessageEventContent {
+                ref body,
+                ref formatted,
+                ..
+            }) => (body, formatted),
+            MessageType::Notice(NoticeMessageEventContent {
+                ref body,
+                ref formatted,
+                ..
+            }) => (body, formatted),
+            _ => return None,
+        };
+        if body.is_empty() {
+            return None;
+        }
+        responder.set_reply_target(ReplyTarget::from_event(room.room_id().clone(), event));
+        responder.answer_to(event_id.clone());
+        if event.content.relates_to.is_none() {
+            responder.set_thread_start(event_id.clone());
+        }
+
+        Some(CommandContext {
+            client,
+            room,
+            sender: event.sender.clone(),
+            event_id: event_id.clone(),
+            is_edit,
+            edit_id: is_edit.then(|| event.event_id.clone()),
+            body: body.clone(),
+            formatted_body: formatted.as_ref().map(|formatted| formatted.body.clone()),
+            thread_root: None,
+            raw_args: String::new(),
+            state,
+            responder,
+            cancellation: CancellationToken::new(),
+            default_timeout: None,
+            room_budget: None,
+            dispatch_slot: DispatchSlot::default(),
+            commands_config: None,
+            thread_unknown: !is_edit && event.content.relates_to.is_some(),
+        })
+    }
+
+    /// The arguments after the command name split using [tokenize]
+    ///
+    /// Fails if the arguments contain a quote which isn't closed.
+    pub fn args(&self) -> Result<Vec<String>, TokenizeError> {
+        tokenize(&self.raw_args)
+    }
+
+    /// Marks the command as used in the thread and posts all answers into it
+    pub fn set_thread_root(&mut self, thread_root: EventId) {
+        self.thread_unknown = false;
+        self.responder.set_thread_root(thread_root.clone());
+        self.thread_root = Some(thread_root);
+    }
+
+    /// Checks if an edit was made by the sender of the message it edits
+    ///
+    /// Servers don't reject edits of messages of other users, so the edited message gets fetched.
+    /// Messages which aren't edits always pass, edits whose message can't be fetched never do.
+    pub async fn edit_by_original_sender(&self) -> bool {
+        let edit_id = match self.edit_id {
+            Some(ref edit_id) => edit_id,
+            None => return true,
+        };
+        let request = get_room_event::Request::new(self.room.room_id(), &self.event_id);
+        let original = match self.client.send(request, None).await {
+            Ok(response) => serde_json::from_str::<EditedEvent>(response.event.json().get()),
+            Err(e) => {
+                tracing::error!("Failed to fetch the edited event {}: {}", self.event_id, e);
+                return false;
+            }
+        };
+        match original {
+            Ok(original) if original.sender == self.sender => true,
+            Ok(original) => {
+                tracing::warn!(
+                    "Ignoring the edit {} by {} of a message of {}",
+                    edit_id,
+                    self.sender,
+                    original.sender
+                );
+                false
+            }
+            Err(e) => {
+                tracing::error!("Failed to read the edited event {}: {}", self.event_id, e);
+                false
+            }
+        }
+    }
+
+    /// Finds the thread in the raw json of the event
+    pub fn read_thread_root(&mut self, event: &RawJsonValue) {
+        self.thread_unknown = false;
+        if let Some(thread_root) = thread::thread_root(event) {
+            self.set_thread_root(thread_root);
+        }
+    }
+
+    /// Fetches the event from the server to find its thread unless it is known already
+    ///
+    /// Only messages with a relation which wasn't read using
+    /// [read_thread_root](CommandContext::read_thread_root) get fetched.
+    pub async fn fetch_thread_root(&mut self) {
+        if !std::mem::take(&mut self.thread_unknown) {
+            return;
+        }
+        let thread_root =
+            thread::fetch_thread_root(&self.client, self.room.room_id(), &self.event_id).await;
+        if let Some(thread_root) = thread_root {
+            self.set_thread_root(thread_root);
+        }
+    }
+}
+
+/// The part of an edited event needed to check the edit
+#[derive(Deserialize)]
+struct EditedEvent {
+    sender: UserId,
+}
+
+/// A parameter of a command which gets taken from the [CommandContext]
+///
+/// The `#[command]` macro recognizes these by the name of the type, so only the ones in this module
+/// can be used. Any other parameter is an argument of the command.
+pub trait FromContext<S> {
+    fn from_context(ctx: &CommandContext<S>) -> Self;
+}
+
+impl<S> FromContext<S> for CommandContext<S> {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.clone()
+    }
+}
+
+impl<S> FromContext<S> for Client {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.client.clone()
+    }
+}
+
+impl<S> FromContext<S> for Joined {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.room.clone()
+    }
+}
+
+impl<S> FromContext<S> for Arc<S> {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.state.clone()
+    }
+}
+
+impl<S> FromContext<S> for Responder {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.responder.clone()
+    }
+}
+
+impl<S> FromContext<S> for CancellationToken {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.cancellation.clone()
+    }
+}
+
+impl<S> FromContext<S> for crate::Sender {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ctx.responder.sender()
+    }
+}
+
+/// The user who used the command
+#[derive(Debug, Clone, PartialEq)]
+pub struct SenderId(pub UserId);
+
+impl<S> FromContext<S> for SenderId {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        SenderId(ctx.sender.clone())
+    }
+}
+
+/// The event containing the command
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerEventId(pub EventId);
+
+impl<S> FromContext<S> for TriggerEventId {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        TriggerEventId(ctx.event_id.clone())
+    }
+}
+
+/// The plain text body of the message containing the command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Body(pub String);
+
+impl<S> FromContext<S> for Body {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        Body(ctx.body.clone())
+    }
+}
+
+/// The html body of the message containing the command
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormattedBody(pub Option<String>);
+
+impl<S> FromContext<S> for FormattedBody {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        FormattedBody(ctx.formatted_body.clone())
+    }
+}
+
+/// The root of the thread the command was used in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadRoot(pub Option<EventId>);
+
+impl<S> FromContext<S> for ThreadRoot {
+    fn from_context(ctx: &CommandContext<S>) -> Self {
+        ThreadRoot(ctx.thread_root.clone())
+    }
+}
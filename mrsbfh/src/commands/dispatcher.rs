@@ -0,0 +1,465 @@
+//! Limiting how many commands run at once
+//!
+//! Without limits every command runs as soon as its message arrives. The [Dispatcher] caps how many
+//! commands run at the same time and can run the commands of each room or user one after another,
+//! so their answers don't get mixed up. The order is best-effort, see [Reservation]. Different
+//! rooms still run in parallel. The code generated by
+//! the `#[command_generate]` macro uses a dispatcher configured by the `dispatch` settings of the
+//! [CommandsConfig](crate::config::CommandsConfig).
+
+use super::{CommandContext, Outgoing, State};
+use crate::config::{DispatchSettings, QueueScope, Rejection};
+use lazy_static::lazy_static;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::{RoomId, UserId};
+use matrix_sdk::Client;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+/// The reply to rejected commands if the config doesn't set a `rejection_message`
+const DEFAULT_REJECTION_MESSAGE: &str =
+    "Too many commands are waiting, try `{command}` again later.";
+
+lazy_static! {
+    /// The dispatcher used by the generated code and the settings it was created with
+    static ref DISPATCHER: Mutex<Option<Arc<Dispatcher>>> = Mutex::new(None);
+    /// The lanes of all rooms and users which have messages in them
+    static ref LANES: Mutex<HashMap<String, Arc<Lane>>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+
+/// Decides when commands may run
+///
+/// ```
+/// use matrix_sdk::ruma::{RoomId, UserId};
+/// use mrsbfh::commands::{Dispatcher, Reservation};
+/// use mrsbfh::config::{DispatchSettings, QueueScope};
+/// use std::convert::TryFrom;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let dispatcher = Dispatcher::new(DispatchSettings {
+///     serialize: Some(QueueScope::Room),
+///     queue_depth: Some(0),
+///     ..Default::default()
+/// });
+/// let room = RoomId::try_from("!room:example.com").unwrap();
+/// let other_room = RoomId::try_from("!other:example.com").unwrap();
+/// let alice = UserId::try_from("@alice:example.com").unwrap();
+///
+/// let permit = dispatcher.acquire(Reservation::new(&room, &alice)).await;
+/// assert!(permit.is_some());
+/// // The room is busy and nothing may wait, other rooms run in parallel
+/// assert!(dispatcher.acquire(Reservation::new(&room, &alice)).await.is_none());
+/// assert!(dispatcher.acquire(Reservation::new(&other_room, &alice)).await.is_some());
+///
+/// drop(permit);
+/// assert!(dispatcher.acquire(Reservation::new(&room, &alice)).await.is_some());
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Dispatcher {
+    settings: DispatchSettings,
+    running: Option<Arc<Semaphore>>,
+    waiting: AtomicUsize,
+}
+
+/// Allows a command to run until it gets dropped
+#[derive(Debug)]
+pub struct DispatchPermit {
+    _lane: Option<Ticket>,
+    _running: Option<OwnedSemaphorePermit>,
+}
+
+/// The place of a message in the lanes of its room and of its sender
+///
+/// The `#[commands]` macro takes the reservation before anything else happens to the message, so
+/// the time spent filtering and parsing it can't reorder the commands. The ordering is best-effort
+/// though: the sdk handles every event in its own task, so messages which arrive in the same sync
+/// can take their places in a different order than they have in the timeline. Messages which turn
+/// out not to contain a known command give up their place by dropping it.
+///
+/// ```
+/// use matrix_sdk::ruma::{RoomId, UserId};
+/// use mrsbfh::commands::{Dispatcher, Reservation};
+/// use mrsbfh::config::{DispatchSettings, QueueScope};
+/// use std::convert::TryFrom;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let dispatcher = Dispatcher::new(DispatchSettings {
+///     serialize: Some(QueueScope::User),
+///     ..Default::default()
+/// });
+/// let room = RoomId::try_from("!room:example.com").unwrap();
+/// let alice = UserId::try_from("@alice:example.com").unwrap();
+///
+/// let not_a_command = Reservation::new(&room, &alice);
+/// let command = Reservation::new(&room, &alice);
+/// drop(not_a_command);
+/// assert!(dispatcher.acquire(command).await.is_some());
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct Reservation {
+    room: Ticket,
+    user: Ticket,
+}
+
+impl Reservation {
+    pub fn new(room_id: &RoomId, sender: &UserId) -> Self {
+        Reservation {
+            room: Ticket::new(room_id.to_string()),
+            user: Ticket::new(format!("{}|{}", room_id, sender)),
+        }
+    }
+
+    /// The ticket of the lane the command has to wait in, the other one gets given up
+    fn into_lane(self, scope: Option<QueueScope>) -> Option<Ticket> {
+        let Reservation { room, user } = self;
+        match scope {
+            Some(QueueScope::Room) => {
+                drop(user);
+                Some(room)
+            }
+            Some(QueueScope::User) => {
+                drop(room);
+                Some(user)
+            }
+            None => None,
+        }
+    }
+}
+
+/// The messages of a room or user in the order they arrived
+#[derive(Debug, Default)]
+struct Lane {
+    tickets: Mutex<VecDeque<Entry>>,
+    changed: Notify,
+}
+
+impl Lane {
+    fn entries(&self) -> std::sync::MutexGuard<'_, VecDeque<Entry>> {
+        self.tickets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    /// If the message contains a command which waits for its turn
+    decided: bool,
+}
+
+/// A place in a lane which is given up once it gets dropped
+#[derive(Debug)]
+struct Ticket {
+    key: String,
+    id: u64,
+    lane: Arc<Lane>,
+}
+
+impl Ticket {
+    fn new(key: String) -> Self {
+        let id = NEXT_TICKET.fetch_add(1, Ordering::SeqCst);
+        let mut lanes = LANES.lock().unwrap_or_else(|e| e.into_inner());
+        let lane = lanes.entry(key.clone()).or_default().clone();
+        lane.entries().push_back(Entry { id, decided: false });
+        Ticket { key, id, lane }
+    }
+
+    /// Marks the message as a command waiting for its turn
+    fn decide(&self) {
+        if let Some(entry) = self.lane.entries().iter_mut().find(|e| e.id == self.id) {
+            entry.decided = true;
+        }
+        self.lane.changed.notify_waiters();
+    }
+
+    /// How many messages are ahead and how many of those are still undecided
+    fn ahead(&self) -> (usize, usize) {
+        let entries = self.lane.entries();
+        let ahead = entries.iter().take_while(|e| e.id != self.id);
+        ahead.fold((0, 0), |(ahead, undecided), e| {
+            (ahead + 1, undecided + usize::from(!e.decided))
+        })
+    }
+
+    /// Waits until `done` returns true for the messages ahead
+    async fn wait_for(&self, done: impl Fn(usize, usize) -> bool) {
+        loop {
+            // Created before checking so a change in between isn't missed
+            let changed = self.lane.changed.notified();
+            let (ahead, undecided) = self.ahead();
+            if done(ahead, undecided) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut lanes = LANES.lock().unwrap_or_else(|e| e.into_inner());
+        let empty = {
+            let mut entries = self.lane.entries();
+            entries.retain(|e| e.id != self.id);
+            entries.is_empty()
+        };
+        if empty
+            && lanes
+                .get(&self.key)
+                .is_some_and(|lane| Arc::ptr_eq(lane, &self.lane))
+        {
+            lanes.remove(&self.key);
+        }
+        self.lane.changed.notify_waiters();
+    }
+}
+
+/// Counts a command as waiting while it exists
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Dispatcher {
+    pub fn new(settings: DispatchSettings) -> Self {
+        Dispatcher {
+            running: settings
+                .max_concurrent
+                .map(|max| Arc::new(Semaphore::new(max))),
+            settings,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Waits until the command holding the reservation may run
+    ///
+    /// Commands of the same lane run in the order they took their [Reservation]. Messages ahead which
+    /// weren't found to be commands yet are waited for without counting as waiting. Returns `None`
+    /// if the command would have to wait while `queue_depth` other commands are waiting already.
+    pub async fn acquire(&self, reservation: Reservation) -> Option<DispatchPermit> {
+        let lane = reservation.into_lane(self.settings.serialize);
+        if let Some(ref lane) = lane {
+            lane.decide();
+            lane.wait_for(|_, undecided| undecided == 0).await;
+        }
+        let lane = match self.try_acquire(lane) {
+            Ok(permit) => return Some(permit),
+            Err(lane) => lane,
+        };
+
+        let waiting = self.waiting.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Waiting(&self.waiting);
+        if self
+            .settings
+            .queue_depth
+            .is_some_and(|queue_depth| waiting >= queue_depth)
+        {
+            return None;
+        }
+
+        if let Some(ref lane) = lane {
+            lane.wait_for(|ahead, _| ahead == 0).await;
+        }
+        let running = match self.running {
+            Some(ref running) => Some(running.clone().acquire_owned().await.ok()?),
+            None => None,
+        };
+        Some(DispatchPermit {
+            _lane: lane,
+            _running: running,
+        })
+    }
+
+    /// Gets the permit if the command doesn't have to wait
+    fn try_acquire(&self, lane: Option<Ticket>) -> Result<DispatchPermit, Option<Ticket>> {
+        if lane.as_ref().is_some_and(|lane| lane.ahead().0 > 0) {
+            return Err(lane);
+        }
+        let running = match self.running {
+            Some(ref running) => match running.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => return Err(lane),
+            },
+            None => None,
+        };
+        Ok(DispatchPermit {
+            _lane: lane,
+            _running: running,
+        })
+    }
+}
+
+/// The place of a message and later the permit of its command, shared by its [CommandContext] and
+/// the code sending its answers
+///
+/// The permit gets released once the command finished and the answers it queued until then were
+/// sent, so the answers of the next command can't overtake them. Messages without a known command
+/// release their place right away.
+///
+/// ```
+/// use matrix_sdk::ruma::{RoomId, UserId};
+/// use mrsbfh::commands::{DispatchSlot, Dispatcher, Reservation};
+/// use mrsbfh::config::{DispatchSettings, QueueScope};
+/// use std::convert::TryFrom;
+/// use std::time::Duration;
+///
+/// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+/// let dispatcher = Dispatcher::new(DispatchSettings {
+///     serialize: Some(QueueScope::User),
+///     ..Default::default()
+/// });
+/// let room = RoomId::try_from("!room:example.com").unwrap();
+/// let alice = UserId::try_from("@alice:example.com").unwrap();
+///
+/// let not_a_command = DispatchSlot::default();
+/// not_a_command.reserve(Reservation::new(&room, &alice));
+/// let command = Reservation::new(&room, &alice);
+///
+/// // Still waiting for the message ahead
+/// let waiting = tokio::time::timeout(Duration::from_millis(50), dispatcher.acquire(command)).await;
+/// assert!(waiting.is_err());
+///
+/// let command = Reservation::new(&room, &alice);
+/// not_a_command.release();
+/// let acquired = tokio::time::timeout(Duration::from_secs(5), dispatcher.acquire(command)).await;
+/// assert!(acquired.unwrap().is_some());
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DispatchSlot(Arc<Mutex<Slot>>);
+
+#[derive(Debug, Default)]
+enum Slot {
+    #[default]
+    Empty,
+    Reserved(Reservation),
+    Admitted {
+        _permit: DispatchPermit,
+    },
+}
+
+impl DispatchSlot {
+    /// Keeps the place of the message until its command gets dispatched
+    pub fn reserve(&self, reservation: Reservation) {
+        *self.lock() = Slot::Reserved(reservation);
+    }
+
+    /// Gives up the place of the message or the permit of its command
+    pub fn release(&self) {
+        *self.lock() = Slot::Empty;
+    }
+
+    fn take_reservation(&self) -> Option<Reservation> {
+        let mut slot = self.lock();
+        match std::mem::take(&mut *slot) {
+            Slot::Reserved(reservation) => Some(reservation),
+            other => {
+                *slot = other;
+                None
+            }
+        }
+    }
+
+    fn fill(&self, permit: DispatchPermit) {
+        *self.lock() = Slot::Admitted { _permit: permit };
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slot> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Sends the answers of a command and releases its slot once the command finished
+///
+/// Answers of tasks the command left running in the background are still sent afterwards but
+/// don't hold up the next command.
+#[doc(hidden)]
+pub async fn send_answers(
+    mut rx: mpsc::Receiver<Outgoing>,
+    mut command: JoinHandle<()>,
+    slot: DispatchSlot,
+    client: &Client,
+    room: &Joined,
+) {
+    loop {
+        tokio::select! {
+            Some(outgoing) = rx.recv() => outgoing.send(client, room).await,
+            _ = &mut command => break,
+        }
+    }
+    while let Ok(outgoing) = rx.try_recv() {
+        outgoing.send(client, room).await;
+    }
+    slot.release();
+    while let Some(outgoing) = rx.recv().await {
+        outgoing.send(client, room).await;
+    }
+}
+
+/// The dispatcher for the settings, recreated if they changed
+///
+/// Commands which are already running keep their permits of the previous dispatcher.
+fn dispatcher(settings: &DispatchSettings) -> Arc<Dispatcher> {
+    let mut dispatcher = DISPATCHER.lock().unwrap_or_else(|e| e.into_inner());
+    match *dispatcher {
+        Some(ref dispatcher) if dispatcher.settings == *settings => dispatcher.clone(),
+        _ => {
+            let new = Arc::new(Dispatcher::new(settings.clone()));
+            *dispatcher = Some(new.clone());
+            new
+        }
+    }
+}
+
+/// Waits until the command may run and tells the sender if it got rejected
+///
+/// The place the message reserved in the `dispatch_slot` of the context gets replaced by the
+/// permit.
+#[doc(hidden)]
+pub async fn dispatch<S: State>(ctx: &CommandContext<S>, command: &str) -> bool {
+    let settings = ctx
+        .commands_config
+        .as_ref()
+        .map(|c| c.dispatch.clone())
+        .unwrap_or_default();
+    let dispatcher = dispatcher(&settings);
+    let reservation = ctx
+        .dispatch_slot
+        .take_reservation()
+        .unwrap_or_else(|| Reservation::new(ctx.room.room_id(), &ctx.sender));
+    match dispatcher.acquire(reservation).await {
+        Some(permit) => {
+            ctx.dispatch_slot.fill(permit);
+            true
+        }
+        None => {
+            tracing::warn!(
+                "Rejecting {} of {} in {} as too many commands are waiting",
+                command,
+                ctx.sender,
+                ctx.room.room_id()
+            );
+            if settings.on_reject == Rejection::Reply {
+                let message = settings
+                    .rejection_message
+                    .as_deref()
+                    .unwrap_or(DEFAULT_REJECTION_MESSAGE)
+                    .replace(
+                        "{command}",
+                        &format!("{}{}", super::command_utils::current_prefix(), command),
+                    );
+                super::send_markdown_notice(&ctx.responder, &message).await;
+            }
+            false
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Cooldowns of commands and the command budgets of rooms
+//!
+//! The checks run before each command and use a [RateLimiter] shared by all commands.
+
+use super::{CommandContext, State};
+use crate::ratelimit::{Budget, Cooldown, CooldownScope, RateLimiter};
+use lazy_static::lazy_static;
+use std::time::Duration;
+
+lazy_static! {
+    static ref LIMITER: RateLimiter = RateLimiter::new();
+}
+
+/// The cooldown of a command generated by the `#[command]` macro
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandCooldown {
+    /// Identifies the command in the limiter, even if several groups have commands with the same
+    /// name
+    pub key: &'static str,
+    /// The cooldown set using `cooldown` and `per`
+    pub cooldown: Option<Cooldown>,
+}
+
+/// Checks the cooldown of a command and tells the sender when they can use it again if not
+///
+/// A cooldown in the [CommandsConfig](crate::config::CommandsConfig) replaces the one of the
+/// command.
+#[doc(hidden)]
+pub async fn check_cooldown<S: State>(
+    ctx: &CommandContext<S>,
+    command: &str,
+    cooldown: &CommandCooldown,
+) -> bool {
+    let configured = ctx
+        .commands_config
+        .as_ref()
+        .and_then(|c| c.cooldowns.get(command).copied());
+    let limit = match configured.or(cooldown.cooldown) {
+        Some(limit) => limit,
+        None => return true,
+    };
+
+    let key = match limit.per {
+        CooldownScope::User => format!("{}|{}", cooldown.key, ctx.sender),
+        CooldownScope::Room => format!("{}|{}", cooldown.key, ctx.room.room_id()),
+        CooldownScope::Global => cooldown.key.to_string(),
+    };
+    match LIMITER.try_acquire(&key, 1, limit.duration) {
+        Ok(()) => true,
+        Err(remaining) => {
+            tracing::info!(
+                "Cooldown of {} for {} in {} still running",
+                command,
+                ctx.sender,
+                ctx.room.room_id()
+            );
+            let markdown = format!(
+                "You are using `{}{}` too often. Try again in {}s.",
+                super::command_utils::current_prefix(),
+                command,
+                whole_seconds(remaining)
+            );
+            super::send_markdown_notice(&ctx.responder, &markdown).await;
+            false
+        }
+    }
+}
+
+/// Checks if the room still has budget left for another command
+///
+/// The `room_budget` of the [CommandsConfig](crate::config::CommandsConfig) replaces the one set
+/// using `#[command_generate(room_budget = "...")]`.
+#[doc(hidden)]
+pub async fn check_room_budget<S: State>(ctx: &CommandContext<S>, budget: Option<Budget>) -> bool {
+    let room_id = ctx.room.room_id();
+    let configured = ctx.commands_config.as_ref().and_then(|c| c.room_budget);
+    let budget = match configured.or(budget) {
+        Some(budget) => budget,
+        None => return true,
+    };
+
+    let key = format!("room budget|{}", room_id);
+    match LIMITER.try_acquire(&key, budget.commands, budget.per) {
+        Ok(()) => true,
+        Err(remaining) => {
+            tracing::info!("Command budget of {} is used up", room_id);
+            let markdown = format!(
+                "This room used too many commands. Try again in {}s.",
+                whole_seconds(remaining)
+            );
+            super::send_markdown_notice(&ctx.responder, &markdown).await;
+            false
+        }
+    }
+}
+
+/// Rounds up so that users never get told to wait `0s`
+pub(super) fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
@@ -0,0 +1,90 @@
+//! Restricting who can use a command
+//!
+//! The `#[command]` macro generates a [Permissions] for each command which gets checked before the
+//! command is called.
+
+use super::{CommandContext, State};
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::UserId;
+
+/// The requirements a sender has to fulfill to use a command
+///
+/// All of the set requirements have to be met.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Permissions {
+    /// The minimum power level in the room set by `min_power_level`
+    pub min_power_level: Option<i64>,
+    /// The sender needs to have at least one of these roles set by `role`
+    pub roles: &'static [&'static str],
+}
+
+impl Permissions {
+    /// If anyone can use the command
+    pub const fn is_unrestricted(&self) -> bool {
+        self.min_power_level.is_none() && self.roles.is_empty()
+    }
+}
+
+/// The reply to denied commands if the config doesn't set a `refusal_message`
+const DEFAULT_REFUSAL_MESSAGE: &str = "You are not allowed to use `{command}`.";
+
+/// Checks if the sender may use the command and replies with the refusal message if not
+///
+/// The roles are looked up in the [CommandsConfig](crate::config::CommandsConfig) of the config.
+/// If the power level of the sender can't be found the command is denied.
+#[doc(hidden)]
+pub async fn check_permissions<S: State>(
+    ctx: &CommandContext<S>,
+    command: &str,
+    permissions: &Permissions,
+) -> bool {
+    if permissions.is_unrestricted() {
+        return true;
+    }
+
+    let commands_config = ctx.commands_config.as_deref();
+    let has_role = permissions.roles.is_empty()
+        || permissions.roles.iter().any(|role| {
+            commands_config
+                .and_then(|c| c.roles.get(*role))
+                .is_some_and(|members| members.iter().any(|member| member == ctx.sender.as_str()))
+        });
+
+    let has_power_level = match permissions.min_power_level {
+        Some(min_power_level) => power_level(&ctx.room, &ctx.sender)
+            .await
+            .is_some_and(|power_level| power_level >= min_power_level),
+        None => true,
+    };
+
+    if has_role && has_power_level {
+        return true;
+    }
+
+    tracing::warn!(
+        "Denied command {} for {} in {} (has role: {}, has power level: {})",
+        command,
+        ctx.sender,
+        ctx.room.room_id(),
+        has_role,
+        has_power_level
+    );
+    let refusal_message = commands_config
+        .and_then(|c| c.refusal_message.as_deref())
+        .unwrap_or(DEFAULT_REFUSAL_MESSAGE);
+    let prefix = super::command_utils::current_prefix();
+    let markdown = refusal_message.replace("{command}", &format!("{}{}", prefix, command));
+    super::send_markdown_notice(&ctx.responder, &markdown).await;
+    false
+}
+
+/// The power level of the user in the room
+async fn power_level(room: &Joined, sender: &UserId) -> Option<i64> {
+    match room.get_member(sender).await {
+        Ok(member) => member.map(|member| member.power_level()),
+        Err(e) => {
+            tracing::error!("Failed to get the power level of {}: {}", sender, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Reacting to the message containing a command with the status of the command
+//!
+//! This is opt-in by setting `reactions` in the [CommandsConfig](crate::config::CommandsConfig).
+//! The code generated by the `#[command_generate]` macro creates the [StatusReactions] once it
+//! recognized a command and finishes them with the result of the command.
+
+use super::{CommandContext, Responder, SentMessage, State};
+use crate::config::Reactions;
+use matrix_sdk::ruma::EventId;
+
+/// The status reactions of a running command
+#[derive(Debug)]
+pub struct StatusReactions {
+    responder: Responder,
+    event_id: EventId,
+    reactions: Reactions,
+    /// The `received` reaction if it could be sent
+    received: Option<SentMessage>,
+}
+
+impl StatusReactions {
+    /// Sends the `received` reaction
+    ///
+    /// Returns `None` if the config doesn't enable reactions.
+    pub async fn received<S: State>(ctx: &CommandContext<S>) -> Option<Self> {
+        let reactions = ctx.commands_config.as_ref()?.reactions.clone()?;
+        let received = react(&ctx.responder, &ctx.event_id, &reactions.received).await;
+        Some(StatusReactions {
+            responder: ctx.responder.clone(),
+            event_id: ctx.event_id.clone(),
+            reactions,
+            received,
+        })
+    }
+
+    /// Sends the `done` or `failed` reaction and removes the `received` one if configured
+    pub async fn finish(self, success: bool) {
+        let emoji = if success {
+            &self.reactions.done
+        } else {
+            &self.reactions.failed
+        };
+        react(&self.responder, &self.event_id, emoji).await;
+
+        if !self.reactions.remove_received {
+            return;
+        }
+        if let Some(mut received) = self.received {
+            if let Some(received) = received.event_id().await {
+                if let Err(e) = self.responder.redact(received.clone(), None).await {
+                    tracing::error!("Failed to remove the reaction {}: {}", received, e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends the reaction unless it is turned off
+async fn react(responder: &Responder, event_id: &EventId, emoji: &str) -> Option<SentMessage> {
+    if emoji.is_empty() {
+        return None;
+    }
+    match responder.react(event_id.clone(), emoji).await {
+        Ok(reaction) => Some(reaction),
+        Err(e) => {
+            tracing::error!("Failed to react to {}: {}", event_id, e);
+            None
+        }
+    }
+}
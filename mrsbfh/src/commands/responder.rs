@@ -0,0 +1,626 @@
+//! Sending the answers of a command
+//!
+//! Everything a command does in a room goes through its [Responder] as an [Action]. The actions
+//! get performed by the code generated by the `#[commands]` macro which reports the event ids back.
+//! This allows editing messages later using the returned [SentMessage].
+//!
+//! The answers to each command are remembered. If the command gets edited and runs again, its
+//! messages edit the previous answers instead of being sent as new messages.
+
+use crate::edit::Responses;
+use crate::errors::DeliveryError;
+use crate::reply::ReplyTarget;
+use lazy_static::lazy_static;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::events::reaction::{ReactionEventContent, Relation as Annotation};
+use matrix_sdk::ruma::events::room::message::{MessageEventContent, MessageType, Relation};
+use matrix_sdk::ruma::events::{AnyMessageEventContent, AnyStateEventContent};
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
+use matrix_sdk::Client;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::oneshot;
+
+lazy_static! {
+    static ref RESPONSES: Responses = Responses::new(1024);
+}
+
+/// Something a command wants to do in a room
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Sends a message
+    Message(AnyMessageEventContent),
+    /// Sets a state event
+    State {
+        content: AnyStateEventContent,
+        state_key: String,
+    },
+    /// Reacts to an event
+    Reaction { event_id: EventId, emoji: String },
+    /// Redacts an event
+    Redaction {
+        event_id: EventId,
+        reason: Option<String>,
+    },
+    /// Invites a user
+    Invite(UserId),
+    /// Shows or hides the typing notice of the bot
+    Typing(bool),
+}
+
+/// An [Action] on its way to the room
+#[derive(Debug)]
+pub struct Outgoing {
+    /// The room to perform the action in or `None` for the room of the command
+    pub room_id: Option<RoomId>,
+    pub action: Action,
+    /// Gets the event id once the action was performed or the error if it failed
+    pub sent: Option<oneshot::Sender<Result<Option<EventId>, DeliveryError>>>,
+    /// The event this message answers
+    pub answer_to: Option<EventId>,
+}
+
+impl Outgoing {
+    /// Performs the action and reports the event id or the error back
+    ///
+    /// Other rooms are looked up using the client. Errors get logged as well.
+    pub async fn send(self, client: &Client, room: &Joined) {
+        let Outgoing {
+            room_id,
+            action,
+            sent,
+            answer_to,
+        } = self;
+        let room = match room_id {
+            None => room.clone(),
+            Some(room_id) => match client.get_joined_room(&room_id) {
+                Some(room) => room,
+                None => {
+                    tracing::error!(
+                        "Can't perform {:?} in {} as it isn't joined",
+                        action,
+                        room_id
+                    );
+                    if let Some(sent) = sent {
+                        let _ = sent.send(Err(DeliveryError::NotJoined(room_id)));
+                    }
+                    return;
+                }
+            },
+        };
+
+        let result: matrix_sdk::Result<Option<EventId>> = match action {
+            Action::Message(content) => room
+                .send(content, None)
+                .await
+                .map(|response| Some(response.event_id)),
+            Action::State { content, state_key } => room
+                .send_state_event(content, &state_key)
+                .await
+                .map(|response| Some(response.event_id))
+                .map_err(Into::into),
+            Action::Reaction { event_id, emoji } => {
+                let content = ReactionEventContent::new(Annotation::new(event_id, emoji));
+                room.send(AnyMessageEventContent::Reaction(content), None)
+                    .await
+                    .map(|response| Some(response.event_id))
+            }
+            Action::Redaction { event_id, reason } => room
+                .redact(&event_id, reason.as_deref(), None)
+                .await
+                .map(|response| Some(response.event_id))
+                .map_err(Into::into),
+            Action::Invite(user_id) => room.invite_user_by_id(&user_id).await.map(|()| None),
+            Action::Typing(typing) => room.typing_notice(typing).await.map(|()| None),
+        };
+
+        let result = match result {
+            Ok(event_id) => {
+                if let (Some(answer_to), Some(event_id)) = (answer_to, &event_id) {
+                    RESPONSES.insert(answer_to, event_id.clone());
+                }
+                Ok(event_id)
+            }
+            Err(e) => {
+                tracing::error!("Failed to perform an action in {}: {}", room.room_id(), e);
+                Err(DeliveryError::from(e))
+            }
+        };
+        if let Some(sent) = sent {
+            // The sender doesn't care about the result anymore if this fails
+            let _ = sent.send(result);
+        }
+    }
+}
+
+/// Sends the answers of a command to the room it was used in and performs its other actions
+///
+/// Messages can be sent as rich replies to the message containing the command using the `reply_*`
+/// methods. With `#[command(reply = true)]` every message of the command becomes a reply.
+///
+/// If the command was used in a thread all messages get posted into the same thread. Answers
+/// longer than the [thread threshold](Responder::set_thread_threshold) start a new thread.
+#[derive(Debug, Clone)]
+pub struct Responder {
+    tx: mpsc::Sender<Outgoing>,
+    reply_target: Option<Arc<ReplyTarget>>,
+    reply_by_default: bool,
+    thread_root: Option<EventId>,
+    thread_start: Option<EventId>,
+    thread_threshold: Option<usize>,
+    answer_to: Option<EventId>,
+    /// The answers of an earlier run of the command which haven't been edited yet
+    previous_answers: Arc<Mutex<VecDeque<EventId>>>,
+}
+
+impl Responder {
+    pub fn new(tx: mpsc::Sender<Outgoing>) -> Self {
+        Responder {
+            tx,
+            reply_target: None,
+            reply_by_default: false,
+            thread_root: None,
+            thread_start: None,
+            thread_threshold: None,
+            answer_to: None,
+            previous_answers: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// A channel for code which still uses [Sender](crate::Sender)
+    ///
+    /// The messages get passed on as they are.
+    pub fn sender(&self) -> crate::Sender {
+        let (tx, mut rx) = mpsc::channel(100);
+        let outgoing = self.tx.clone();
+        tokio::spawn(async move {
+            while let Some(content) = rx.recv().await {
+                let message = Outgoing {
+                    room_id: None,
+                    action: Action::Message(content),
+                    sent: None,
+                    answer_to: None,
+                };
+                if outgoing.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tx
+    }
+
+    /// The message which the `reply_*` methods reply to
+    pub fn reply_target(&self) -> Option<&ReplyTarget> {
+        self.reply_target.as_deref()
+    }
+
+    pub fn set_reply_target(&mut self, reply_target: ReplyTarget) {
+        self.reply_target = Some(Arc::new(reply_target));
+    }
+
+    /// Makes all messages replies, even the ones sent using [send](Responder::send)
+    pub fn set_reply_by_default(&mut self, reply_by_default: bool) {
+        self.reply_by_default = reply_by_default;
+    }
+
+    /// The root of the thread the messages get posted into
+    pub fn thread_root(&self) -> Option<&EventId> {
+        self.thread_root.as_ref()
+    }
+
+    /// Posts all messages into the thread
+    ///
+    /// Using the id of the message containing the command starts a new thread on it.
+    pub fn set_thread_root(&mut self, thread_root: EventId) {
+        self.thread_root = Some(thread_root);
+    }
+
+    /// The message a new thread gets started on for long answers
+    ///
+    /// Threads can't be started on messages which have a relation themselves.
+    pub fn set_thread_start(&mut self, thread_start: EventId) {
+        self.thread_start = Some(thread_start);
+    }
+
+    /// Remembers the messages sent as answers to the event
+    ///
+    /// If there are answers from an earlier run they get edited instead of sending new messages.
+    pub fn answer_to(&mut self, event_id: EventId) {
+        let previous_answers = RESPONSES.get(&event_id);
+        self.previous_answers = Arc::new(Mutex::new(previous_answers.into()));
+        self.answer_to = Some(event_id);
+    }
+
+    /// Messages with more lines than this start a thread if they aren't in one already
+    pub fn set_thread_threshold(&mut self, thread_threshold: Option<usize>) {
+        self.thread_threshold = thread_threshold;
+    }
+
+    /// Sends any message
+    ///
+    /// Room messages without a relation become replies if
+    /// [set_reply_by_default](Responder::set_reply_by_default) is enabled. Room messages which
+    /// aren't edits get posted into the thread.
+    pub async fn send(
+        &self,
+        content: AnyMessageEventContent,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        match content {
+            AnyMessageEventContent::RoomMessage(message) => {
+                let reply = self.reply_by_default && message.relates_to.is_none();
+                self.send_answer(message, reply).await
+            }
+            content => self.send_outgoing(content, None).await,
+        }
+    }
+
+    /// Sends a plain text notice
+    pub async fn send_notice(
+        &self,
+        body: impl Into<String>,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.send_message(MessageEventContent::notice_plain(body.into()))
+            .await
+    }
+
+    /// Renders the markdown to html and sends both as a notice
+    pub async fn send_markdown_notice(
+        &self,
+        markdown: &str,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.send_message(markdown_notice(markdown)).await
+    }
+
+    /// Sends the message as a reply to the message containing the command
+    pub async fn reply(
+        &self,
+        message: MessageEventContent,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.send_answer(message, true).await
+    }
+
+    /// Sends a plain text notice as a reply
+    pub async fn reply_notice(
+        &self,
+        body: impl Into<String>,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.reply(MessageEventContent::notice_plain(body.into()))
+            .await
+    }
+
+    /// Sends a plain text message as a reply
+    pub async fn reply_text(
+        &self,
+        body: impl Into<String>,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.reply(MessageEventContent::text_plain(body.into()))
+            .await
+    }
+
+    /// Renders the markdown to html and sends both as a notice reply
+    pub async fn reply_markdown_notice(
+        &self,
+        markdown: &str,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.reply(markdown_notice(markdown)).await
+    }
+
+    /// Sends a message to another room the bot is in
+    ///
+    /// The message is sent as it is, without becoming a reply or part of a thread.
+    pub async fn send_to(
+        &self,
+        room_id: RoomId,
+        content: AnyMessageEventContent,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.perform(Some(room_id), Action::Message(content), None)
+            .await
+            .map_err(|SendError(action)| SendError(message_content(action)))
+    }
+
+    /// Sets a state event in the room of the command
+    pub async fn send_state_event(
+        &self,
+        content: impl Into<AnyStateEventContent>,
+        state_key: &str,
+    ) -> Result<SentMessage, SendError<Action>> {
+        let action = Action::State {
+            content: content.into(),
+            state_key: state_key.to_string(),
+        };
+        self.act(action).await
+    }
+
+    /// Reacts to an event in the room of the command
+    pub async fn react(
+        &self,
+        event_id: EventId,
+        emoji: impl Into<String>,
+    ) -> Result<SentMessage, SendError<Action>> {
+        let action = Action::Reaction {
+            event_id,
+            emoji: emoji.into(),
+        };
+        self.act(action).await
+    }
+
+    /// Redacts an event in the room of the command
+    pub async fn redact(
+        &self,
+        event_id: EventId,
+        reason: Option<String>,
+    ) -> Result<SentMessage, SendError<Action>> {
+        self.act(Action::Redaction { event_id, reason }).await
+    }
+
+    /// Invites the user to the room of the command and waits until that is done
+    pub async fn invite(&self, user_id: UserId) -> Result<(), DeliveryError> {
+        self.perform_and_wait(Action::Invite(user_id)).await
+    }
+
+    /// Shows or hides the typing notice of the bot in the room of the command and waits until that
+    /// is done
+    pub async fn typing(&self, typing: bool) -> Result<(), DeliveryError> {
+        self.perform_and_wait(Action::Typing(typing)).await
+    }
+
+    /// Sends any message like [send](Responder::send) and waits until it is in the room
+    ///
+    /// Resolves to the event id of the message or the error which prevented sending it.
+    pub async fn deliver(&self, content: AnyMessageEventContent) -> Result<EventId, DeliveryError> {
+        match self.send(content).await {
+            Ok(message) => message.delivered().await,
+            Err(_) => Err(DeliveryError::NotSent),
+        }
+    }
+
+    /// Performs the action in the room of the command
+    ///
+    /// Invites and typing notices don't create an event, so their [SentMessage] resolves to
+    /// [DeliveryError::NoEvent] if they succeeded.
+    pub async fn act(&self, action: Action) -> Result<SentMessage, SendError<Action>> {
+        self.perform(None, action, None).await
+    }
+
+    /// Performs the action in another room the bot is in
+    pub async fn act_in(
+        &self,
+        room_id: RoomId,
+        action: Action,
+    ) -> Result<SentMessage, SendError<Action>> {
+        self.perform(Some(room_id), action, None).await
+    }
+
+    async fn send_message(
+        &self,
+        message: MessageEventContent,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.send(AnyMessageEventContent::RoomMessage(message))
+            .await
+    }
+
+    /// Sends a room message answering the command
+    ///
+    /// Edits the next answer of an earlier run of the command if there is one.
+    async fn send_answer(
+        &self,
+        message: MessageEventContent,
+        reply: bool,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        if let Some(Relation::Replacement(_)) = message.relates_to {
+            let content = AnyMessageEventContent::RoomMessage(message);
+            return self.send_outgoing(content, None).await;
+        }
+
+        if let Some(previous) = self.next_previous_answer() {
+            let edit = crate::edit::replace(previous.clone(), message);
+            let mut sent = self
+                .send_outgoing(AnyMessageEventContent::RoomMessage(edit), None)
+                .await?;
+            if let EventIdState::Pending(pending) = sent.event_id {
+                sent.event_id = EventIdState::Editing {
+                    original: previous,
+                    pending,
+                };
+            }
+            return Ok(sent);
+        }
+
+        let message = if reply {
+            self.as_reply(message)
+        } else {
+            message
+        };
+        let content = self.in_thread(message);
+        self.send_outgoing(content, self.answer_to.clone()).await
+    }
+
+    async fn send_outgoing(
+        &self,
+        content: AnyMessageEventContent,
+        answer_to: Option<EventId>,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        self.perform(None, Action::Message(content), answer_to)
+            .await
+            .map_err(|SendError(action)| SendError(message_content(action)))
+    }
+
+    async fn perform(
+        &self,
+        room_id: Option<RoomId>,
+        action: Action,
+        answer_to: Option<EventId>,
+    ) -> Result<SentMessage, SendError<Action>> {
+        let (sent, event_id) = oneshot::channel();
+        let message = Outgoing {
+            room_id,
+            action,
+            sent: Some(sent),
+            answer_to,
+        };
+        match self.tx.send(message).await {
+            Ok(()) => Ok(SentMessage {
+                responder: self.clone(),
+                event_id: EventIdState::Pending(event_id),
+            }),
+            Err(SendError(message)) => Err(SendError(message.action)),
+        }
+    }
+
+    async fn perform_and_wait(&self, action: Action) -> Result<(), DeliveryError> {
+        let (sent, result) = oneshot::channel();
+        let message = Outgoing {
+            room_id: None,
+            action,
+            sent: Some(sent),
+            answer_to: None,
+        };
+        if self.tx.send(message).await.is_err() {
+            return Err(DeliveryError::NotSent);
+        }
+        match result.await {
+            Ok(result) => result.map(|_| ()),
+            Err(_) => Err(DeliveryError::NotSent),
+        }
+    }
+
+    fn next_previous_answer(&self) -> Option<EventId> {
+        let mut previous_answers = self
+            .previous_answers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        previous_answers.pop_front()
+    }
+
+    /// Sends the message unchanged if there is no message to reply to
+    fn as_reply(&self, message: MessageEventContent) -> MessageEventContent {
+        match self.reply_target {
+            Some(ref reply_target) => reply_target.reply_to(message),
+            None => message,
+        }
+    }
+
+    /// Posts the message into the thread of the command or starts one if the message is too long
+    fn in_thread(&self, message: MessageEventContent) -> AnyMessageEventContent {
+        let root = match (self.thread_root.as_ref(), self.thread_threshold) {
+            (Some(root), _) => root,
+            (None, Some(threshold)) if line_count(&message) > threshold => {
+                match self.thread_start {
+                    Some(ref root) => root,
+                    None => return AnyMessageEventContent::RoomMessage(message),
+                }
+            }
+            _ => return AnyMessageEventContent::RoomMessage(message),
+        };
+        let latest = match self.reply_target {
+            Some(ref reply_target) => &reply_target.event_id,
+            None => root,
+        };
+        crate::thread::in_thread(message, root, latest)
+    }
+}
+
+/// A message or other event sent by a [Responder]
+#[derive(Debug)]
+pub struct SentMessage {
+    responder: Responder,
+    event_id: EventIdState,
+}
+
+#[derive(Debug)]
+enum EventIdState {
+    Pending(oneshot::Receiver<Result<Option<EventId>, DeliveryError>>),
+    /// An edit of the answer of an earlier run which stands in for that answer once it was sent
+    Editing {
+        original: EventId,
+        pending: oneshot::Receiver<Result<Option<EventId>, DeliveryError>>,
+    },
+    Sent(EventId),
+    Failed(DeliveryError),
+}
+
+impl SentMessage {
+    /// Waits until the message was sent and returns its event id
+    ///
+    /// Returns `None` if sending the message failed, see [delivered](SentMessage::delivered) for
+    /// the reason.
+    pub async fn event_id(&mut self) -> Option<&EventId> {
+        let resolved = match self.event_id {
+            EventIdState::Pending(ref mut pending) => Some(match pending.await {
+                Ok(Ok(Some(event_id))) => EventIdState::Sent(event_id),
+                Ok(Ok(None)) => EventIdState::Failed(DeliveryError::NoEvent),
+                Ok(Err(e)) => EventIdState::Failed(e),
+                Err(_) => EventIdState::Failed(DeliveryError::NotSent),
+            }),
+            EventIdState::Editing {
+                ref original,
+                ref mut pending,
+            } => Some(match pending.await {
+                Ok(Ok(_)) => EventIdState::Sent(original.clone()),
+                Ok(Err(e)) => EventIdState::Failed(e),
+                Err(_) => EventIdState::Failed(DeliveryError::NotSent),
+            }),
+            EventIdState::Sent(_) | EventIdState::Failed(_) => None,
+        };
+        if let Some(resolved) = resolved {
+            self.event_id = resolved;
+        }
+        match self.event_id {
+            EventIdState::Sent(ref event_id) => Some(event_id),
+            _ => None,
+        }
+    }
+
+    /// Waits until the message was sent and returns its event id or the error which prevented it
+    pub async fn delivered(mut self) -> Result<EventId, DeliveryError> {
+        self.event_id().await;
+        match self.event_id {
+            EventIdState::Sent(event_id) => Ok(event_id),
+            EventIdState::Failed(e) => Err(e),
+            EventIdState::Pending(_) | EventIdState::Editing { .. } => Err(DeliveryError::NotSent),
+        }
+    }
+
+    /// Replaces the content of the message
+    ///
+    /// This waits until the message was sent. If that failed the edit gets returned as an error.
+    pub async fn edit(
+        &mut self,
+        message: MessageEventContent,
+    ) -> Result<SentMessage, SendError<AnyMessageEventContent>> {
+        let event_id = match self.event_id().await {
+            Some(event_id) => event_id.clone(),
+            None => return Err(SendError(AnyMessageEventContent::RoomMessage(message))),
+        };
+        let edit = crate::edit::replace(event_id, message);
+        self.responder
+            .send_outgoing(AnyMessageEventContent::RoomMessage(edit), None)
+            .await
+    }
+}
+
+/// The content of a message action which couldn't be sent
+fn message_content(action: Action) -> AnyMessageEventContent {
+    match action {
+        Action::Message(content) => content,
+        _ => unreachable!("only messages are sent as messages"),
+    }
+}
+
+fn line_count(message: &MessageEventContent) -> usize {
+    match message.msgtype {
+        MessageType::Text(ref content) => content.body.lines().count(),
+        MessageType::Notice(ref content) => content.body.lines().count(),
+        MessageType::Emote(ref content) => content.body.lines().count(),
+        _ => 0,
+    }
+}
+
+fn markdown_notice(markdown: &str) -> MessageEventContent {
+    let parser = pulldown_cmark::Parser::new_ext(markdown, pulldown_cmark::Options::empty());
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    MessageEventContent::notice_html(markdown, html)
+}
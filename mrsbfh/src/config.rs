@@ -29,12 +29,64 @@
 //! }
 //! ```
 //!
+//! ## Runtime settings for commands
+//!
+//! Some behaviour of the generated command handling can be changed at runtime. To do this add a
+//! field of the type [CommandsConfig](crate::config::CommandsConfig) to your config. The derive
+//! macro picks it up automatically.
+//!
+//! ```compile_fail
+//! #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, ConfigDerive)]
+//! pub struct Config<'a> {
+//!     // ...
+//!     #[serde(default)]
+//!     pub commands: mrsbfh::config::CommandsConfig,
+//! }
+//! ```
+//!
+//! In yaml this looks like:
+//!
+//! ```yaml
+//! commands:
+//!   prefixes: ["!", "?"]
+//!   roles:
+//!     admin: ["@alice:example.com"]
+//!   refusal_message: "Only admins can use `{command}`."
+//!   cooldowns:
+//!     expensive_report:
+//!       duration: 5m
+//!       per: room
+//!   room_budget:
+//!     commands: 20
+//!     per: 1m
+//!   thread_threshold: 10
+//!   reactions:
+//!     received: "⏳"
+//!     remove_received: true
+//!   rerun_edits: true
+//!   filter:
+//!     ignored_users: ["@otherbot:example.com"]
+//!   startup_grace: 30s
+//!   processed_events_path: ./data/processed_events
+//!   default_timeout: 2m
+//!   dispatch:
+//!     max_concurrent: 8
+//!     serialize: room
+//!     queue_depth: 50
+//!     on_reject: reply
+//!     rejection_message: "The bot is busy, try `{command}` again later."
+//! ```
+//!
 
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::errors::ConfigError;
+use crate::ratelimit::{Budget, Cooldown};
+use crate::sync::MessageFilter;
 
 pub use mrsbfh_macros::ConfigDerive;
 
@@ -42,4 +94,115 @@ pub trait Loader {
     fn load<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self, ConfigError>
     where
         Self: Sized + Serialize + DeserializeOwned;
+
+    /// The runtime settings for the commands if the config has any
+    fn commands_config(&self) -> Option<&CommandsConfig> {
+        None
+    }
+}
+
+/// Settings to override the defaults of the generated command handling at runtime
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    /// Replaces the prefixes set using `#[command_generate(prefix = ...)]`
+    pub prefixes: Option<Vec<String>>,
+    /// The MXIDs of the users which have a role used by `#[command(role = ...)]`
+    pub roles: HashMap<String, Vec<String>>,
+    /// The reply if someone isn't allowed to use a command. `{command}` gets replaced with the
+    /// command.
+    pub refusal_message: Option<String>,
+    /// Replaces the `cooldown` of commands by their name
+    pub cooldowns: HashMap<String, Cooldown>,
+    /// Replaces the budget set using `#[command_generate(room_budget = ...)]`
+    pub room_budget: Option<Budget>,
+    /// Answers with more lines than this start a thread on the message containing the command
+    pub thread_threshold: Option<usize>,
+    /// Reacts to the message containing a command with its status if set
+    pub reactions: Option<Reactions>,
+    /// Runs commands again if their message gets edited. The answers of the earlier run get
+    /// edited instead of sending new messages.
+    pub rerun_edits: bool,
+    /// Decides which messages are ignored, like the ones of other bots
+    pub filter: MessageFilter,
+    /// Messages sent longer than this before the start of the bot are ignored, so the initial
+    /// sync doesn't run old commands again. Defaults to
+    /// [DEFAULT_STARTUP_GRACE](crate::sync::DEFAULT_STARTUP_GRACE).
+    #[serde(with = "crate::utils::option_duration_str")]
+    pub startup_grace: Option<Duration>,
+    /// A file to remember the handled messages in, so commands never run twice even after a
+    /// restart with a fresh store
+    pub processed_events_path: Option<PathBuf>,
+    /// Replaces the default timeout set using `#[command_generate(timeout = ...)]`
+    #[serde(with = "crate::utils::option_duration_str")]
+    pub default_timeout: Option<Duration>,
+    /// Limits how many commands run at the same time and in which order
+    pub dispatch: DispatchSettings,
+}
+
+/// The limits of the [Dispatcher](crate::commands::Dispatcher)
+///
+/// Nothing is limited by default.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DispatchSettings {
+    /// How many commands may run at the same time
+    pub max_concurrent: Option<usize>,
+    /// Runs the commands of each room or user one after another
+    pub serialize: Option<QueueScope>,
+    /// How many commands may wait before new ones get rejected
+    pub queue_depth: Option<usize>,
+    /// What happens to rejected commands
+    pub on_reject: Rejection,
+    /// The reply to rejected commands. `{command}` gets replaced with the command.
+    pub rejection_message: Option<String>,
+}
+
+/// Whose commands run one after another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueScope {
+    /// All commands of a room
+    Room,
+    /// The commands of each user in a room
+    User,
+}
+
+/// What happens to commands rejected because too many are waiting
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rejection {
+    /// Tells the sender using the `rejection_message`
+    #[default]
+    Reply,
+    /// Drops the command without an answer
+    Ignore,
+}
+
+/// The reactions showing the status of a command
+///
+/// The pending reaction gets sent once a command is recognized, one of the others when it finished.
+/// An empty string turns a reaction off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Reactions {
+    /// Shows that the command was received and is running
+    pub received: String,
+    /// Shows that the command finished successfully
+    pub done: String,
+    /// Shows that the command returned an error
+    pub failed: String,
+    /// Removes the `received` reaction once the command finished
+    pub remove_received: bool,
+}
+
+impl Default for Reactions {
+    fn default() -> Self {
+        Reactions {
+            received: "👀".to_string(),
+            done: "✅".to_string(),
+            failed: "❌".to_string(),
+            remove_received: true,
+        }
+    }
 }
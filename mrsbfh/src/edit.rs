@@ -0,0 +1,111 @@
+//! # Edits
+//!
+//! Messages get edited by sending a new message with the `m.replace` relation. [replace] builds it
+//! including the fallback for clients which don't support edits.
+//!
+//! ```
+//! use matrix_sdk::ruma::events::room::message::{MessageEventContent, MessageType, Relation};
+//! use matrix_sdk::ruma::EventId;
+//! use std::convert::TryFrom;
+//!
+//! let event_id = EventId::try_from("$progress:example.com").unwrap();
+//! let edit = mrsbfh::edit::replace(event_id, MessageEventContent::notice_plain("Working… 20%"));
+//! match edit.msgtype {
+//!     MessageType::Notice(notice) => assert_eq!(notice.body, "* Working… 20%"),
+//!     _ => unreachable!(),
+//! }
+//! assert!(matches!(edit.relates_to, Some(Relation::Replacement(_))));
+//! ```
+//!
+//! To edit its answers when a command gets edited the bot remembers them in [Responses]:
+//!
+//! ```
+//! use matrix_sdk::ruma::EventId;
+//! use mrsbfh::edit::Responses;
+//! use std::convert::TryFrom;
+//!
+//! let responses = Responses::new(100);
+//! let command = EventId::try_from("$command:example.com").unwrap();
+//! let answer = EventId::try_from("$answer:example.com").unwrap();
+//!
+//! responses.insert(command.clone(), answer.clone());
+//! assert_eq!(responses.get(&command), vec![answer]);
+//! ```
+
+use matrix_sdk::ruma::events::room::message::{
+    MessageEventContent, MessageType, Relation, Replacement,
+};
+use matrix_sdk::ruma::EventId;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Creates the edit replacing the content of the message with `event_id`
+///
+/// The relation of the new content gets dropped as edits can't change it.
+pub fn replace(event_id: EventId, mut new_content: MessageEventContent) -> MessageEventContent {
+    new_content.relates_to = None;
+    let mut fallback = new_content.clone();
+    let text = match fallback.msgtype {
+        MessageType::Text(ref mut content) => Some((&mut content.body, &mut content.formatted)),
+        MessageType::Notice(ref mut content) => Some((&mut content.body, &mut content.formatted)),
+        MessageType::Emote(ref mut content) => Some((&mut content.body, &mut content.formatted)),
+        _ => None,
+    };
+    if let Some((body, formatted)) = text {
+        *body = format!("* {}", body);
+        if let Some(formatted) = formatted {
+            formatted.body = format!("* {}", formatted.body);
+        }
+    }
+    fallback.relates_to = Some(Relation::Replacement(Replacement::new(
+        event_id,
+        Box::new(new_content),
+    )));
+    fallback
+}
+
+/// The answers the bot sent to messages
+///
+/// Only the messages of the last `capacity` events are kept.
+#[derive(Debug)]
+pub struct Responses {
+    capacity: usize,
+    inner: Mutex<ResponsesInner>,
+}
+
+#[derive(Debug, Default)]
+struct ResponsesInner {
+    /// The events in the order they were first answered
+    order: VecDeque<EventId>,
+    responses: HashMap<EventId, Vec<EventId>>,
+}
+
+impl Responses {
+    pub fn new(capacity: usize) -> Self {
+        Responses {
+            capacity,
+            inner: Mutex::new(ResponsesInner::default()),
+        }
+    }
+
+    /// Remembers that `response` was sent as an answer to `event_id`
+    pub fn insert(&self, event_id: EventId, response: EventId) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if !inner.responses.contains_key(&event_id) {
+            inner.order.push_back(event_id.clone());
+        }
+        inner.responses.entry(event_id).or_default().push(response);
+
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.responses.remove(&oldest);
+            }
+        }
+    }
+
+    /// The answers to `event_id` in the order they were sent
+    pub fn get(&self, event_id: &EventId) -> Vec<EventId> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.responses.get(event_id).cloned().unwrap_or_default()
+    }
+}
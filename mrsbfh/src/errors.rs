@@ -2,6 +2,57 @@
 
 use thiserror::Error;
 
+/// Decides what users get to see of the errors returned by commands
+///
+/// The full error always gets logged together with a correlation id. By default the user gets a
+/// short notice containing the same id, so the error can be found in the logs. Implementing
+/// [user_message](CommandError::user_message) allows showing more or nothing at all.
+///
+/// ```
+/// use mrsbfh::errors::CommandError;
+///
+/// #[derive(Debug, thiserror::Error)]
+/// enum Error {
+///     #[error("The deployment of {0} failed")]
+///     DeploymentFailed(String),
+///     #[error(transparent)]
+///     IOError(#[from] std::io::Error),
+/// }
+///
+/// impl CommandError for Error {
+///     fn user_message(&self, correlation_id: &str) -> Option<String> {
+///         match self {
+///             Error::DeploymentFailed(_) => Some(self.to_string()),
+///             _ => Some(mrsbfh::errors::default_user_message(correlation_id)),
+///         }
+///     }
+/// }
+///
+/// let error = Error::DeploymentFailed("staging".to_string());
+/// assert_eq!(error.user_message("1a2b3c4d").unwrap(), "The deployment of staging failed");
+/// ```
+pub trait CommandError: std::fmt::Display {
+    /// The markdown message for the user or `None` to not answer at all
+    fn user_message(&self, correlation_id: &str) -> Option<String> {
+        Some(default_user_message(correlation_id))
+    }
+}
+
+/// The message users get for errors by default
+pub fn default_user_message(correlation_id: &str) -> String {
+    format!(
+        "Something went wrong while running the command. The error was logged with the id `{}`.",
+        correlation_id
+    )
+}
+
+/// A command which panicked instead of returning, holding the panic message
+#[derive(Error, Debug)]
+#[error("The command panicked: {0}")]
+pub struct CommandPanic(pub String);
+
+impl CommandError for CommandPanic {}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error(transparent)]
@@ -17,3 +68,47 @@ pub enum SessionError {
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
 }
+
+/// Errors that can happen while parsing the typed arguments of a command
+#[derive(Error, Debug)]
+pub enum ArgError {
+    #[error("Missing argument `{0}`")]
+    Missing(&'static str),
+    #[error("Invalid value `{value}` for argument `{name}`: {reason}")]
+    Invalid {
+        name: &'static str,
+        value: String,
+        reason: String,
+    },
+    #[error("Unexpected arguments: `{0}`")]
+    TooMany(String),
+    #[error(transparent)]
+    Tokenize(#[from] TokenizeError),
+}
+
+/// Errors that can happen while splitting a message into arguments
+#[derive(Error, Debug, PartialEq)]
+pub enum TokenizeError {
+    #[error("Missing closing `{0}`")]
+    UnterminatedQuote(char),
+}
+
+/// Errors that can happen while parsing a duration like `30s`
+#[derive(Error, Debug, PartialEq)]
+pub enum DurationError {
+    #[error("Invalid duration `{0}`, expected something like `30s`, `5m` or `1h30m`")]
+    Invalid(String),
+}
+
+/// Errors that can happen while performing an action of a command
+#[derive(Error, Debug)]
+pub enum DeliveryError {
+    #[error("The action was dropped before it could be performed")]
+    NotSent,
+    #[error("The bot is not in the room {0}")]
+    NotJoined(matrix_sdk::ruma::RoomId),
+    #[error("The action doesn't create an event")]
+    NoEvent,
+    #[error(transparent)]
+    MatrixError(#[from] matrix_sdk::Error),
+}
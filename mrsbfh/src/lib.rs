@@ -28,14 +28,18 @@ pub mod commands;
 #[cfg(feature = "macros")]
 pub mod config;
 
+pub mod edit;
 pub mod errors;
+pub mod ratelimit;
+pub mod reply;
 pub mod sync;
+pub mod thread;
 pub mod utils;
 
 /// A wrapper type for the tokio sender channel with AnyMessageEventContent as content needed in multiple places
 pub type Sender = tokio::sync::mpsc::Sender<matrix_sdk::ruma::events::AnyMessageEventContent>;
 
-/// An extension to simply do notices
+/// An extension to simply do notices and replies
 #[async_trait::async_trait]
 pub trait MatrixMessageExt {
     async fn send_notice(
@@ -46,6 +50,28 @@ pub trait MatrixMessageExt {
         (),
         tokio::sync::mpsc::error::SendError<matrix_sdk::ruma::events::AnyMessageEventContent>,
     >;
+
+    /// Sends a notice as a rich reply to the target
+    async fn reply_notice(
+        &mut self,
+        in_reply_to: &reply::ReplyTarget,
+        body: String,
+        formatted_body: Option<String>,
+    ) -> Result<
+        (),
+        tokio::sync::mpsc::error::SendError<matrix_sdk::ruma::events::AnyMessageEventContent>,
+    >;
+
+    /// Sends a text message as a rich reply to the target
+    async fn reply_text(
+        &mut self,
+        in_reply_to: &reply::ReplyTarget,
+        body: String,
+        formatted_body: Option<String>,
+    ) -> Result<
+        (),
+        tokio::sync::mpsc::error::SendError<matrix_sdk::ruma::events::AnyMessageEventContent>,
+    >;
 }
 
 #[async_trait::async_trait]
@@ -79,8 +105,59 @@ impl MatrixMessageExt for Sender {
             }
         }
     }
+
+    async fn reply_notice(
+        &mut self,
+        in_reply_to: &reply::ReplyTarget,
+        body: String,
+        formatted_body: Option<String>,
+    ) -> Result<
+        (),
+        tokio::sync::mpsc::error::SendError<matrix_sdk::ruma::events::AnyMessageEventContent>,
+    > {
+        let content = match formatted_body {
+            Some(formatted_body) => {
+                matrix_sdk::ruma::events::room::message::MessageEventContent::notice_html(
+                    body,
+                    formatted_body,
+                )
+            }
+            None => {
+                matrix_sdk::ruma::events::room::message::MessageEventContent::notice_plain(body)
+            }
+        };
+        let content = matrix_sdk::ruma::events::AnyMessageEventContent::RoomMessage(
+            in_reply_to.reply_to(content),
+        );
+        self.send(content).await
+    }
+
+    async fn reply_text(
+        &mut self,
+        in_reply_to: &reply::ReplyTarget,
+        body: String,
+        formatted_body: Option<String>,
+    ) -> Result<
+        (),
+        tokio::sync::mpsc::error::SendError<matrix_sdk::ruma::events::AnyMessageEventContent>,
+    > {
+        let content = match formatted_body {
+            Some(formatted_body) => {
+                matrix_sdk::ruma::events::room::message::MessageEventContent::text_html(
+                    body,
+                    formatted_body,
+                )
+            }
+            None => matrix_sdk::ruma::events::room::message::MessageEventContent::text_plain(body),
+        };
+        let content = matrix_sdk::ruma::events::AnyMessageEventContent::RoomMessage(
+            in_reply_to.reply_to(content),
+        );
+        self.send(content).await
+    }
 }
 
+pub use async_trait;
 pub use serde_yaml;
 pub use tokio;
 pub use tracing;
@@ -0,0 +1,111 @@
+//! # Rate limiting
+//!
+//! The [RateLimiter] counts how often a key (like a user or a room) was used in a sliding window.
+//! The generated command handling uses it for the `cooldown` of commands and the room budgets, but
+//! it can be used on its own as well.
+//!
+//! ```
+//! use mrsbfh::ratelimit::RateLimiter;
+//! use std::time::Duration;
+//!
+//! let limiter = RateLimiter::new();
+//! let window = Duration::from_secs(60);
+//!
+//! assert!(limiter.try_acquire("@alice:example.com", 2, window).is_ok());
+//! assert!(limiter.try_acquire("@alice:example.com", 2, window).is_ok());
+//! // The third use within a minute gets rejected with the time until the next one is possible
+//! assert!(limiter.try_acquire("@alice:example.com", 2, window).is_err());
+//! assert!(limiter.try_acquire("@bob:example.com", 2, window).is_ok());
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys are cleaned up once the limiter tracks more than this many
+const CLEANUP_THRESHOLD: usize = 1024;
+
+/// Counts uses of keys in a sliding window
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    uses: Mutex<HashMap<String, Uses>>,
+}
+
+#[derive(Debug)]
+struct Uses {
+    window: Duration,
+    times: VecDeque<Instant>,
+}
+
+impl Uses {
+    fn forget_expired(&mut self, now: Instant) {
+        while let Some(oldest) = self.times.front() {
+            if now.duration_since(*oldest) < self.window {
+                break;
+            }
+            self.times.pop_front();
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a use of the key if it was used less than `max` times in the last `window`
+    ///
+    /// Otherwise the time until the next use is possible is returned.
+    pub fn try_acquire(&self, key: &str, max: u32, window: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut uses = self.uses.lock().unwrap_or_else(|e| e.into_inner());
+        if uses.len() > CLEANUP_THRESHOLD {
+            uses.retain(|_, uses| {
+                uses.forget_expired(now);
+                !uses.times.is_empty()
+            });
+        }
+
+        let entry = uses.entry(key.to_string()).or_insert_with(|| Uses {
+            window,
+            times: VecDeque::new(),
+        });
+        entry.window = window;
+        entry.forget_expired(now);
+        if entry.times.len() < max as usize {
+            entry.times.push_back(now);
+            return Ok(());
+        }
+        let oldest = entry.times.front().copied().unwrap_or(now);
+        Err(window.saturating_sub(now.duration_since(oldest)))
+    }
+}
+
+/// Who shares a cooldown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownScope {
+    /// Every user has their own cooldown
+    User,
+    /// All users of a room share the cooldown
+    Room,
+    /// Everyone shares the cooldown
+    Global,
+}
+
+/// The time a command can't be used again after it was used
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Cooldown {
+    #[serde(with = "crate::utils::duration_str")]
+    pub duration: Duration,
+    pub per: CooldownScope,
+}
+
+/// How many commands can be used in a room within a time window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    pub commands: u32,
+    #[serde(with = "crate::utils::duration_str")]
+    pub per: Duration,
+}
@@ -0,0 +1,173 @@
+//! # Rich replies
+//!
+//! A [ReplyTarget] describes the message a reply refers to and turns any message into a rich reply
+//! with the `m.in_reply_to` relation and the quoted fallback for clients which don't support
+//! replies.
+//!
+//! ```
+//! use matrix_sdk::ruma::events::room::message::{MessageEventContent, MessageType};
+//! use mrsbfh::reply::ReplyTarget;
+//! use std::convert::TryFrom;
+//!
+//! let target = ReplyTarget {
+//!     room_id: matrix_sdk::ruma::RoomId::try_from("!room:example.com").unwrap(),
+//!     event_id: matrix_sdk::ruma::EventId::try_from("$event:example.com").unwrap(),
+//!     sender: matrix_sdk::ruma::UserId::try_from("@alice:example.com").unwrap(),
+//!     body: "!ping".to_string(),
+//!     formatted_body: None,
+//! };
+//!
+//! let reply = target.reply_to(MessageEventContent::notice_plain("pong"));
+//! assert!(reply.relates_to.is_some());
+//! match reply.msgtype {
+//!     MessageType::Notice(notice) => assert_eq!(notice.body, "> <@alice:example.com> !ping\n\npong"),
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use matrix_sdk::ruma::events::room::message::{
+    FormattedBody, InReplyTo, MessageEventContent, MessageType, Relation,
+};
+use matrix_sdk::ruma::events::SyncMessageEvent;
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
+
+/// The message a reply refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyTarget {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub sender: UserId,
+    /// The plain text body of the message
+    pub body: String,
+    /// The html body of the message if it has one
+    pub formatted_body: Option<String>,
+}
+
+impl ReplyTarget {
+    /// The target for replies to the event
+    ///
+    /// Replies to edits refer to the edited message with its new content. The sender of the edit
+    /// is taken as the sender of the message, edits by anyone else get dropped by `match_command`
+    /// using [CommandContext::edit_by_original_sender](crate::commands::CommandContext::edit_by_original_sender).
+    pub fn from_event(room_id: RoomId, event: &SyncMessageEvent<MessageEventContent>) -> Self {
+        let (content, event_id) = match event.content.relates_to {
+            Some(Relation::Replacement(ref replacement)) => {
+                (&*replacement.new_content, &replacement.event_id)
+            }
+            _ => (&event.content, &event.event_id),
+        };
+        let (body, formatted_body) = match content.msgtype {
+            MessageType::Text(ref content) => (content.body.clone(), html(&content.formatted)),
+            MessageType::Notice(ref content) => (content.body.clone(), html(&content.formatted)),
+            MessageType::Emote(ref content) => {
+                (format!("* {}", content.body), html(&content.formatted))
+            }
+            MessageType::Image(_) => ("sent an image.".to_string(), None),
+            MessageType::Video(_) => ("sent a video.".to_string(), None),
+            MessageType::Audio(_) => ("sent an audio file.".to_string(), None),
+            MessageType::File(_) => ("sent a file.".to_string(), None),
+            _ => ("sent a message.".to_string(), None),
+        };
+        ReplyTarget {
+            room_id,
+            event_id: event_id.clone(),
+            sender: event.sender.clone(),
+            body,
+            formatted_body,
+        }
+    }
+
+    /// Turns the message into a reply to the target
+    ///
+    /// Text, notice and emote messages get the quoted fallback prepended to their body. Any existing
+    /// relation of the message is replaced.
+    pub fn reply_to(&self, mut content: MessageEventContent) -> MessageEventContent {
+        let plain_fallback = self.plain_fallback();
+        let html_fallback = self.html_fallback();
+        let (body, formatted) = match content.msgtype {
+            MessageType::Text(ref mut content) => (&mut content.body, &mut content.formatted),
+            MessageType::Notice(ref mut content) => (&mut content.body, &mut content.formatted),
+            MessageType::Emote(ref mut content) => (&mut content.body, &mut content.formatted),
+            _ => {
+                content.relates_to = Some(self.relation());
+                return content;
+            }
+        };
+
+        let html_reply = match formatted.take() {
+            Some(formatted) => formatted.body,
+            None => escape_html(body).replace('\n', "<br>"),
+        };
+        *formatted = Some(FormattedBody::html(format!(
+            "{}{}",
+            html_fallback, html_reply
+        )));
+        *body = format!("{}\n\n{}", plain_fallback, body);
+
+        content.relates_to = Some(self.relation());
+        content
+    }
+
+    fn relation(&self) -> Relation {
+        Relation::Reply {
+            in_reply_to: InReplyTo::new(self.event_id.clone()),
+        }
+    }
+
+    /// Every line of the original message quoted with the sender in front
+    fn plain_fallback(&self) -> String {
+        let mut lines = strip_plain_fallback(&self.body).lines();
+        let first = lines.next().unwrap_or_default();
+        let mut fallback = format!("> <{}> {}", self.sender, first);
+        for line in lines {
+            fallback.push_str("\n> ");
+            fallback.push_str(line);
+        }
+        fallback
+    }
+
+    fn html_fallback(&self) -> String {
+        let body = match self.formatted_body {
+            Some(ref formatted_body) => strip_html_fallback(formatted_body).to_string(),
+            None => escape_html(strip_plain_fallback(&self.body)).replace('\n', "<br>"),
+        };
+        format!(
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/{room_id}/{event_id}\">In reply to</a> <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br>{body}</blockquote></mx-reply>",
+            room_id = self.room_id,
+            event_id = self.event_id,
+            sender = self.sender,
+            body = body,
+        )
+    }
+}
+
+fn html(formatted: &Option<FormattedBody>) -> Option<String> {
+    formatted.as_ref().map(|formatted| formatted.body.clone())
+}
+
+/// Removes the fallback of a message which is a reply itself
+fn strip_plain_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    match body.find("\n\n") {
+        Some(end) => &body[end + 2..],
+        None => body,
+    }
+}
+
+fn strip_html_fallback(formatted_body: &str) -> &str {
+    match formatted_body.find("</mx-reply>") {
+        Some(end) if formatted_body.starts_with("<mx-reply>") => {
+            &formatted_body[end + "</mx-reply>".len()..]
+        }
+        _ => formatted_body,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -1,12 +1,36 @@
 //! # Helpers for the sync process
 
+use lazy_static::lazy_static;
 use matrix_sdk::{
     room::Room,
-    ruma::events::{room::member::MemberEventContent, StrippedStateEvent},
+    ruma::events::{
+        room::member::MemberEventContent,
+        room::message::{MessageEventContent, MessageType},
+        StrippedStateEvent, SyncMessageEvent,
+    },
+    ruma::{EventId, UserId},
     Client,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use tracing::*;
 
+lazy_static! {
+    static ref STARTUP_TIME: SystemTime = SystemTime::now();
+    static ref PROCESSED_EVENTS: Mutex<HashMap<PathBuf, ProcessedEvents>> =
+        Mutex::new(HashMap::new());
+}
+
+/// How long before the start of the bot messages still get handled if nothing else is configured
+pub const DEFAULT_STARTUP_GRACE: Duration = Duration::from_secs(10);
+
+/// How many event ids [mark_processed] remembers
+const PROCESSED_EVENTS_CAPACITY: usize = 1024;
+
 /// A small helper to auto join any incitation
 ///
 /// To join just do this:
@@ -53,3 +77,229 @@ pub async fn autojoin(
         info!("Successfully joined room {}", room.room_id());
     }
 }
+
+/// Decides which messages are ignored to prevent loops with the bot itself and other bots
+///
+/// The `#[commands]` macro uses the one in the [CommandsConfig](crate::config::CommandsConfig).
+/// By default messages of the bot itself and notices are ignored.
+///
+/// ```
+/// use matrix_sdk::ruma::UserId;
+/// use mrsbfh::sync::MessageFilter;
+/// use std::convert::TryFrom;
+///
+/// let filter = MessageFilter {
+///     ignored_users: vec!["@otherbot:example.com".to_string()],
+///     ..MessageFilter::default()
+/// };
+/// let own_user_id = UserId::try_from("@bot:example.com").unwrap();
+///
+/// assert!(filter.allows_sender(Some(&own_user_id), &UserId::try_from("@alice:example.com").unwrap()));
+/// assert!(!filter.allows_sender(Some(&own_user_id), &own_user_id));
+/// assert!(!filter.allows_sender(Some(&own_user_id), &UserId::try_from("@otherbot:example.com").unwrap()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageFilter {
+    /// Ignores messages of the bot account, also the ones sent from its other devices
+    pub ignore_own: bool,
+    /// Ignores `m.notice` messages which are meant to be sent by bots
+    pub ignore_notices: bool,
+    /// The MXIDs of users whose messages get ignored, like other bots
+    pub ignored_users: Vec<String>,
+}
+
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter {
+            ignore_own: true,
+            ignore_notices: true,
+            ignored_users: Vec::new(),
+        }
+    }
+}
+
+impl MessageFilter {
+    /// If messages of the sender get handled
+    ///
+    /// `own_user_id` is the MXID of the bot.
+    pub fn allows_sender(&self, own_user_id: Option<&UserId>, sender: &UserId) -> bool {
+        if self.ignore_own && own_user_id == Some(sender) {
+            return false;
+        }
+        !self
+            .ignored_users
+            .iter()
+            .any(|ignored| ignored == sender.as_str())
+    }
+
+    /// If the message gets handled
+    pub async fn allows(
+        &self,
+        client: &Client,
+        event: &SyncMessageEvent<MessageEventContent>,
+    ) -> bool {
+        if self.ignore_notices && matches!(event.content.msgtype, MessageType::Notice(_)) {
+            return false;
+        }
+        let own_user_id = client.user_id().await;
+        let allowed = self.allows_sender(own_user_id.as_ref(), &event.sender);
+        if !allowed {
+            debug!("Ignoring message {} of {}", event.event_id, event.sender);
+        }
+        allowed
+    }
+}
+
+/// The time the bot started
+///
+/// This is the time of the first call, so call it early in `main` to have it pinned before the
+/// first sync. Otherwise it gets pinned by the first message.
+pub fn startup_time() -> SystemTime {
+    *STARTUP_TIME
+}
+
+/// If the message was sent after the start of the bot or at most `grace` before it
+///
+/// Compares the `origin_server_ts` of the event with [startup_time]. Messages without a valid
+/// timestamp count as sent before the start.
+pub fn sent_after_startup(event: &SyncMessageEvent<MessageEventContent>, grace: Duration) -> bool {
+    let sent = match event.origin_server_ts.to_system_time() {
+        Some(sent) => sent,
+        None => return false,
+    };
+    let earliest = startup_time()
+        .checked_sub(grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    sent >= earliest
+}
+
+/// Remembers the event as processed in the file at `path`
+///
+/// Returns `false` if it was processed before, also if that was before a restart. Only the latest
+/// event ids are kept. If the file can't be written the event is still remembered until the bot
+/// stops. The file gets accessed on the blocking thread pool of tokio.
+pub async fn mark_processed(path: &Path, event_id: &EventId) -> bool {
+    let path = path.to_path_buf();
+    let event_id = event_id.clone();
+    let marked = tokio::task::spawn_blocking(move || {
+        let mut processed = PROCESSED_EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+        processed
+            .entry(path.clone())
+            .or_insert_with(|| ProcessedEvents::load(path, PROCESSED_EVENTS_CAPACITY))
+            .insert(&event_id)
+    })
+    .await;
+    marked.unwrap_or_else(|e| {
+        error!("Failed to mark the event as processed: {}", e);
+        true
+    })
+}
+
+/// The ids of processed events, one per line in a file
+///
+/// New ids get appended and the file gets rewritten with only the latest `capacity` ids once it
+/// grew to twice that size.
+///
+/// ```
+/// use matrix_sdk::ruma::EventId;
+/// use mrsbfh::sync::ProcessedEvents;
+/// use std::convert::TryFrom;
+///
+/// let path = std::env::temp_dir().join("mrsbfh-doctest-processed-events");
+/// let _ = std::fs::remove_file(&path);
+/// let event_id = EventId::try_from("$command:example.com").unwrap();
+///
+/// let mut processed = ProcessedEvents::load(path.clone(), 100);
+/// assert!(processed.insert(&event_id));
+/// assert!(!processed.insert(&event_id));
+///
+/// // The id is still known after a restart
+/// let mut processed = ProcessedEvents::load(path.clone(), 100);
+/// assert!(!processed.insert(&event_id));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ProcessedEvents {
+    path: PathBuf,
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+    lines_in_file: usize,
+}
+
+impl ProcessedEvents {
+    /// Reads the ids from the file if it exists
+    pub fn load(path: PathBuf, capacity: usize) -> Self {
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                error!("Failed to read the processed events from {:?}: {}", path, e);
+                String::new()
+            }
+        };
+        let mut processed = ProcessedEvents {
+            path,
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+            lines_in_file: 0,
+        };
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            processed.lines_in_file += 1;
+            processed.remember(line.to_string());
+        }
+        processed
+    }
+
+    /// Remembers the event id and returns `false` if it was already known
+    pub fn insert(&mut self, event_id: &EventId) -> bool {
+        if self.ids.contains(event_id.as_str()) {
+            return false;
+        }
+        self.remember(event_id.to_string());
+        if let Err(e) = self.save(event_id) {
+            error!(
+                "Failed to save the processed events to {:?}: {}",
+                self.path, e
+            );
+        }
+        true
+    }
+
+    fn remember(&mut self, event_id: String) {
+        if !self.ids.insert(event_id.clone()) {
+            return;
+        }
+        self.order.push_back(event_id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    fn save(&mut self, event_id: &EventId) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if self.lines_in_file >= self.capacity * 2 {
+            let mut content = String::new();
+            for id in &self.order {
+                content.push_str(id);
+                content.push('\n');
+            }
+            std::fs::write(&self.path, content)?;
+            self.lines_in_file = self.order.len();
+            return Ok(());
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", event_id)?;
+        self.lines_in_file += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! # Threads
+//!
+//! The used versions of the sdk don't know the `m.thread` relation yet. This module finds the
+//! thread of an event in its raw json and puts messages into a thread.
+//!
+//! ```
+//! use matrix_sdk::ruma::events::room::message::MessageEventContent;
+//! use matrix_sdk::ruma::events::AnyMessageEventContent;
+//! use matrix_sdk::ruma::EventId;
+//! use std::convert::TryFrom;
+//!
+//! let event = serde_json::value::RawValue::from_string(
+//!     r#"{
+//!         "type": "m.room.message",
+//!         "content": {
+//!             "msgtype": "m.text",
+//!             "body": "!ping",
+//!             "m.relates_to": { "rel_type": "m.thread", "event_id": "$root:example.com" }
+//!         }
+//!     }"#
+//!     .to_string(),
+//! )
+//! .unwrap();
+//! let root = mrsbfh::thread::thread_root(&event).unwrap();
+//! assert_eq!(root.as_str(), "$root:example.com");
+//!
+//! let latest = EventId::try_from("$ping:example.com").unwrap();
+//! let content = mrsbfh::thread::in_thread(MessageEventContent::notice_plain("pong"), &root, &latest);
+//! match content {
+//!     AnyMessageEventContent::_Custom(content) => {
+//!         assert_eq!(content.data["m.relates_to"]["rel_type"], "m.thread");
+//!         assert_eq!(content.data["m.relates_to"]["is_falling_back"], true);
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use matrix_sdk::ruma::api::client::r0::room::get_room_event;
+use matrix_sdk::ruma::events::custom::CustomEventContent;
+use matrix_sdk::ruma::events::room::message::{MessageEventContent, Relation};
+use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::{EventId, RoomId};
+use matrix_sdk::Client;
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+/// The `rel_type` of threads and the one used before it was added to the spec
+const THREAD_REL_TYPES: &[&str] = &["m.thread", "io.element.thread"];
+
+#[derive(Deserialize)]
+struct Event {
+    content: Content,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    #[serde(rename = "m.relates_to")]
+    relates_to: Option<RelatesTo>,
+}
+
+#[derive(Deserialize)]
+struct RelatesTo {
+    rel_type: Option<String>,
+    event_id: Option<String>,
+}
+
+/// The root of the thread the event is part of
+///
+/// Returns `None` if the event isn't in a thread or isn't a valid event.
+pub fn thread_root(event: &RawJsonValue) -> Option<EventId> {
+    let event: Event = serde_json::from_str(event.get()).ok()?;
+    let relates_to = event.content.relates_to?;
+    if !THREAD_REL_TYPES.contains(&relates_to.rel_type?.as_str()) {
+        return None;
+    }
+    EventId::try_from(relates_to.event_id?).ok()
+}
+
+/// Fetches the event from the server to find the root of its thread
+///
+/// For when the raw json of the event isn't available. The relation stays readable in encrypted
+/// rooms as it is not encrypted.
+pub async fn fetch_thread_root(
+    client: &Client,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Option<EventId> {
+    let request = get_room_event::Request::new(room_id, event_id);
+    match client.send(request, None).await {
+        Ok(response) => thread_root(response.event.json()),
+        Err(e) => {
+            tracing::error!("Failed to fetch the event {}: {}", event_id, e);
+            None
+        }
+    }
+}
+
+/// Puts the message into the thread
+///
+/// Replies keep replying to their message. Other messages reply to `latest` for clients which
+/// don't support threads.
+pub fn in_thread(
+    message: MessageEventContent,
+    root: &EventId,
+    latest: &EventId,
+) -> AnyMessageEventContent {
+    let (in_reply_to, is_falling_back) = match message.relates_to {
+        Some(Relation::Reply { ref in_reply_to }) => (&in_reply_to.event_id, false),
+        _ => (latest, true),
+    };
+    let relates_to = json!({
+        "rel_type": "m.thread",
+        "event_id": root,
+        "is_falling_back": is_falling_back,
+        "m.in_reply_to": { "event_id": in_reply_to },
+    });
+
+    let mut data = match serde_json::to_value(&message) {
+        Ok(Value::Object(data)) => data,
+        _ => return AnyMessageEventContent::RoomMessage(message),
+    };
+    data.insert("m.relates_to".to_string(), relates_to);
+    AnyMessageEventContent::_Custom(CustomEventContent {
+        event_type: "m.room.message".to_string(),
+        data: data.into_iter().collect(),
+    })
+}
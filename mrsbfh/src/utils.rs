@@ -54,10 +54,17 @@
 //!
 //! If not it creates and saves the [Session](crate::utils::Session) struct. Allowing for a relogin on the next start.
 //!
+//! ## Durations
+//!
+//! Durations in the macros and the config are written like `500ms`, `30s`, `5m` or `1h30m`. They
+//! are parsed using [parse_duration] and can be used in serde structs with
+//! `#[serde(with = "mrsbfh::utils::duration_str")]`.
+//!
 
-use crate::errors::SessionError;
+use crate::errors::{DurationError, SessionError};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::Duration;
 use tracing::*;
 
 /// Informations needed to keep track about a session
@@ -101,3 +108,87 @@ impl Session {
         }
     }
 }
+
+/// Parses a duration like `500ms`, `30s`, `5m`, `2h`, `1d` or combinations like `1h30m`
+///
+/// ```
+/// use mrsbfh::utils::parse_duration;
+/// use std::time::Duration;
+///
+/// assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+/// assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+/// assert!(parse_duration("30").is_err());
+/// ```
+pub fn parse_duration(input: &str) -> Result<Duration, DurationError> {
+    let invalid = || DurationError::Invalid(input.to_string());
+    let mut rest = input.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut duration = Duration::ZERO;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let millis = match &rest[..unit_len] {
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60 * 1_000,
+            "h" => 60 * 60 * 1_000,
+            "d" => 24 * 60 * 60 * 1_000,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[unit_len..];
+        let millis = value.checked_mul(millis).ok_or_else(invalid)?;
+        duration += Duration::from_millis(millis);
+    }
+    Ok(duration)
+}
+
+/// (De)serializes a [Duration] as a string like `30s` using [parse_duration]
+pub mod duration_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        super::parse_duration(&input).map_err(serde::de::Error::custom)
+    }
+}
+
+/// (De)serializes an optional [Duration] like [duration_str]
+pub mod option_duration_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::duration_str::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(input) => super::parse_duration(&input)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
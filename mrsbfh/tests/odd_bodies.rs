@@ -0,0 +1,179 @@
+//! Message bodies which aren't commands or are written in unusual ways must never panic the
+//! command handling.
+
+use matrix_sdk::ruma::UserId;
+use mrsbfh::commands::catch_panic;
+use mrsbfh::commands::command_utils::{
+    edit_distance, parse_command, parse_mention_command, strip_mention, tokenize,
+};
+use std::convert::TryFrom;
+
+const ODD_BODIES: &[&str] = &[
+    "",
+    " ",
+    "   \t\n",
+    "\u{a0}",
+    "\u{200b}",
+    "!",
+    "! ",
+    "!!",
+    "!\u{200b}",
+    "!\u{a0}ping",
+    "!\"",
+    "!'",
+    "!\\",
+    "!ping \"unterminated",
+    "é",
+    "é!ping",
+    "!é",
+    "!🤖",
+    "🤖",
+    "🤖 ",
+    "🤖ping",
+    "¡",
+    "¡ping",
+    "> quoted\n\n!ping",
+    "%",
+    "@",
+    ":",
+    "Bot",
+    "Bot:",
+    "Bot: ",
+    "Bot:!",
+    "@bot:example.com",
+    "@bot:example.co",
+    "\u{0130}",
+    "ǅ",
+];
+
+const PREFIXES: &[&str] = &["!", "?bot ", "¡", "🤖 ", ""];
+
+fn bot() -> UserId {
+    UserId::try_from("@bot:example.com").unwrap()
+}
+
+#[test]
+fn parse_command_handles_odd_bodies() {
+    for body in ODD_BODIES {
+        let _ = parse_command(body, PREFIXES);
+    }
+}
+
+#[test]
+fn whitespace_is_not_a_command() {
+    for body in ["", " ", "   \t\n", "! ", "!\u{a0}ping", "🤖 "] {
+        assert!(parse_command(body, PREFIXES).is_none(), "{:?}", body);
+    }
+}
+
+#[test]
+fn non_ascii_prefixes() {
+    let command = parse_command("¡ping now", PREFIXES).unwrap();
+    assert_eq!(command.prefix, "¡");
+    assert_eq!(command.name, "ping");
+    assert_eq!(command.rest, "now");
+
+    let command = parse_command("🤖   ping", PREFIXES).unwrap();
+    assert_eq!(command.prefix, "🤖 ");
+    assert_eq!(command.name, "ping");
+
+    assert!(parse_command("🤖", PREFIXES).is_none());
+    assert!(parse_command("🤖ping", PREFIXES).is_none());
+}
+
+#[test]
+fn non_ascii_command_names() {
+    let command = parse_command("!Écrire", PREFIXES).unwrap();
+    assert_eq!(command.name, "écrire");
+
+    assert!(parse_command("!🤖", PREFIXES).is_none());
+    assert!(parse_command("!\u{200b}", PREFIXES).is_none());
+}
+
+#[test]
+fn unterminated_quotes_are_errors() {
+    // Only the arguments of known commands get split, so the name is found regardless
+    let command = parse_command("!ping \"unterminated", PREFIXES).unwrap();
+    assert_eq!(command.name, "ping");
+    assert!(tokenize(&command.rest).is_err());
+    assert!(parse_command("!othercmd don't", PREFIXES).is_some());
+    assert!(parse_command("!\"", PREFIXES).is_none());
+    assert!(tokenize("'").is_err());
+}
+
+#[test]
+fn tokenize_handles_odd_bodies() {
+    for body in ODD_BODIES {
+        let _ = tokenize(body);
+    }
+    assert_eq!(tokenize("\\").unwrap(), vec!["\\"]);
+    assert!(tokenize("\"\\").is_err());
+    assert_eq!(tokenize("\"\"").unwrap(), vec![""]);
+}
+
+#[test]
+fn mentions_handle_odd_bodies() {
+    let formatted_bodies = [
+        None,
+        Some(""),
+        Some("<a href="),
+        Some("<a href=\"https://matrix.to/#/%\">Bot</a>: ping"),
+        Some("<a href=\"https://matrix.to/#/%4\">Bot</a>: ping"),
+        Some("<a href=\"https://matrix.to/#/%zz\">Bot</a>: ping"),
+        Some("<a href=\"https://matrix.to/#/%40bot%3Aexample.com\">é</a>"),
+    ];
+    for body in ODD_BODIES {
+        for formatted_body in formatted_bodies {
+            for display_name in [None, Some(""), Some("é"), Some("Bot"), Some("🤖")] {
+                let _ = strip_mention(body, formatted_body, &bot(), display_name);
+                let _ = parse_mention_command(body, formatted_body, &bot(), display_name, PREFIXES);
+            }
+        }
+    }
+}
+
+#[test]
+fn mention_names_in_the_middle_of_characters() {
+    // The length of the name ends inside of the `é`
+    assert!(strip_mention("aé ping", None, &bot(), Some("ab")).is_none());
+    assert!(strip_mention("Bot", None, &bot(), Some("Bot")).is_none());
+
+    let (mention, rest) = strip_mention("é: ping", None, &bot(), Some("É")).unwrap();
+    assert_eq!(mention, "é: ");
+    assert_eq!(rest, "ping");
+}
+
+#[test]
+fn mention_without_command() {
+    for body in ["Bot:", "Bot: ", "Bot:  \t", "Bot: !"] {
+        assert!(
+            parse_mention_command(body, None, &bot(), Some("Bot"), PREFIXES).is_none(),
+            "{:?}",
+            body
+        );
+    }
+}
+
+#[test]
+fn edit_distance_counts_characters() {
+    assert_eq!(edit_distance("", ""), 0);
+    assert_eq!(edit_distance("é", "e"), 1);
+    assert_eq!(edit_distance("🤖", ""), 1);
+}
+
+#[tokio::test]
+async fn panics_are_caught() {
+    let panic = catch_panic(async {
+        let body = "";
+        body.chars().next().expect("This is not a command")
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(panic.0, "This is not a command");
+
+    let panic = catch_panic(async { panic!("{} failed", "ping") })
+        .await
+        .unwrap_err();
+    assert_eq!(panic.0, "ping failed");
+    assert_eq!(panic.to_string(), "The command panicked: ping failed");
+}